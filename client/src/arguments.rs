@@ -1,4 +1,6 @@
+use orosu::api::client::DEFAULT_TOKEN_LIFETIME;
 use orosu::configuration::LogLevelConfiguration;
+use std::path::PathBuf;
 
 #[derive(Debug, clap::Parser)]
 #[command(version, about, long_about = None)]
@@ -8,8 +10,26 @@ pub struct CliArguments {
     pub address: String,
     #[clap(short, long)]
     pub script: String,
-    #[clap(short, long)]
-    pub key: String,
+    #[clap(
+        short,
+        long,
+        allow_hyphen_values = true,
+        required_unless_present_any = ["oidc_token", "oidc_audience"]
+    )]
+    pub key: Option<String>,
+    /// OIDC token of the CI workflow, used instead of a key. The server must
+    /// allow unbound tokens, prefer --oidc-audience in GitHub Actions
+    #[clap(
+        long,
+        env = "OROSU_OIDC_TOKEN",
+        conflicts_with = "key",
+        hide_env_values = true
+    )]
+    pub oidc_token: Option<String>,
+    /// Request a GitHub Actions OIDC token for this audience, bound to a key
+    /// that signs the task request, and use it instead of a key
+    #[clap(long, env = "OROSU_OIDC_AUDIENCE", conflicts_with_all = ["key", "oidc_token"])]
+    pub oidc_audience: Option<String>,
     #[clap(short, long)]
     pub retries: Option<u8>,
     #[clap(short, long, default_value = "info")]
@@ -18,4 +38,22 @@ pub struct CliArguments {
     pub file: Option<Vec<String>>,
     #[clap(short, long, default_value_t = 65536)]
     pub chunk_size: usize,
+    #[clap(long)]
+    pub audience: Option<String>,
+    /// Lifetime of the authentication token in seconds, raise it if the client clock drifts.
+    /// The server rejects tokens valid for longer than its max_token_lifetime
+    #[clap(long, default_value_t = DEFAULT_TOKEN_LIFETIME)]
+    pub token_lifetime: u64,
+    /// PEM file with the client certificate, for servers that require one
+    #[clap(long, requires = "client_key")]
+    pub client_certificate: Option<PathBuf>,
+    /// PEM file with the private key of the client certificate
+    #[clap(long, requires = "client_certificate")]
+    pub client_key: Option<PathBuf>,
+    /// PEM file with the certificate authorities trusted to issue the server certificate
+    #[clap(long)]
+    pub ca_certificates: Option<PathBuf>,
+    /// Client name, overrides the name stored in the key or the OpenSSH key comment
+    #[clap(long)]
+    pub client_name: Option<String>,
 }
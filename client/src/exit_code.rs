@@ -0,0 +1,51 @@
+//! Exit codes of the client when the script itself did not run to an exit
+//! code. They follow sysexits.h, so scripts should avoid exiting with 65-78
+//! for pipelines to tell them apart.
+
+use orosu::api::ServerErrorResponse;
+use orosu::api::client::ConnectionError;
+
+/// The request or the attachment was rejected.
+pub const INVALID_DATA: i32 = 65;
+/// The server could not be reached or the connection broke.
+pub const UNAVAILABLE: i32 = 69;
+/// The server failed to start or follow the script.
+pub const SERVER_ERROR: i32 = 70;
+/// The client failed on its own, e.g. with a malformed key or an attachment
+/// it cannot read.
+pub const CLIENT_ERROR: i32 = 71;
+/// The server is busy or the client is rate limited.
+pub const RETRY_LATER: i32 = 75;
+/// The client and server protocol versions are incompatible.
+pub const PROTOCOL_MISMATCH: i32 = 76;
+/// Authentication failed or the script is not allowed for the client.
+pub const NOT_PERMITTED: i32 = 77;
+/// The script is not configured for the client.
+pub const SCRIPT_NOT_FOUND: i32 = 78;
+
+pub fn of(error: &anyhow::Error) -> i32 {
+    if let Some(error) = error.downcast_ref::<ServerErrorResponse>() {
+        return match error {
+            ServerErrorResponse::InvalidRequest { .. }
+            | ServerErrorResponse::AttachmentTooLarge { .. }
+            | ServerErrorResponse::InvalidAttachment { .. }
+            | ServerErrorResponse::HashMismatch { .. } => INVALID_DATA,
+            ServerErrorResponse::CannotLaunchScript { .. }
+            | ServerErrorResponse::Unknown { .. } => SERVER_ERROR,
+            ServerErrorResponse::ProtocolMismatch { .. } => PROTOCOL_MISMATCH,
+            ServerErrorResponse::InvalidSignature { .. }
+            | ServerErrorResponse::Forbidden { .. } => NOT_PERMITTED,
+            ServerErrorResponse::ScriptNotFound { .. } => SCRIPT_NOT_FOUND,
+        };
+    }
+    match error.downcast_ref::<ConnectionError>() {
+        Some(ConnectionError::Rejected {
+            status: 401 | 403, ..
+        }) => NOT_PERMITTED,
+        Some(ConnectionError::Rejected {
+            status: 429 | 503, ..
+        }) => RETRY_LATER,
+        Some(ConnectionError::Rejected { .. } | ConnectionError::Failed(_)) => UNAVAILABLE,
+        None => CLIENT_ERROR,
+    }
+}
@@ -1,28 +1,68 @@
 use crate::arguments::CliArguments;
 use anyhow::Context;
 use clap::Parser;
-use orosu::api::client::ApiClient;
-use orosu::cryptography::ClientKey;
+use orosu::api::client::{ApiClient, Authentication, ConnectOptions};
+use orosu::api::oidc_token::BoundOidcToken;
+use orosu::cryptography::Credential;
 use orosu::server_address::ServerAddress;
 use tracing::level_filters::LevelFilter;
 
 mod arguments;
+mod exit_code;
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() {
     let arguments = CliArguments::parse();
 
     tracing_subscriber::fmt()
-        .with_max_level(LevelFilter::from_level(arguments.log_level.into()))
+        .with_max_level(LevelFilter::from_level(arguments.log_level.clone().into()))
         .compact()
         .init();
 
-    let key = ClientKey::from_string(arguments.key)?;
+    let code = match run(arguments).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            exit_code::of(&e)
+        }
+    };
+    std::process::exit(code);
+}
+
+/// Runs the task and returns the exit code of the script.
+async fn run(arguments: CliArguments) -> anyhow::Result<i32> {
+    let authentication = match (arguments.key, arguments.oidc_token, arguments.oidc_audience) {
+        (_, _, Some(audience)) => {
+            Authentication::BoundOidcToken(BoundOidcToken::request_github(&audience).await?)
+        }
+        (_, Some(token), None) => Authentication::OidcToken(token),
+        (Some(key), None, None) => {
+            let mut credential = Credential::from_string(key)?;
+            if let Some(client_name) = arguments.client_name {
+                credential.key.client_name = client_name;
+            }
+            if credential.key.client_name.is_empty() {
+                anyhow::bail!("client name is missing in the key, pass it with --client-name");
+            }
+            Authentication::Key(credential)
+        }
+        (None, None, None) => {
+            anyhow::bail!("either --key, --oidc-token or --oidc-audience is required")
+        }
+    };
     let address = ServerAddress::from_string(arguments.address)?;
 
     let files = arguments.file.unwrap_or_default();
 
-    let client = ApiClient::connect(address, key)
+    let options = ConnectOptions {
+        audience: arguments.audience,
+        token_lifetime: Some(arguments.token_lifetime),
+        client_certificate: arguments.client_certificate,
+        client_key: arguments.client_key,
+        ca_certificates: arguments.ca_certificates,
+    };
+
+    let client = ApiClient::connect(address, authentication, options)
         .await
         .context("failed to connect to server")?;
 
@@ -33,7 +73,5 @@ async fn main() -> anyhow::Result<()> {
             files,
             arguments.chunk_size,
         )
-        .await?;
-
-    Ok(())
+        .await
 }
@@ -1,12 +1,125 @@
+use chrono::{DateTime, Utc};
+use cidr::IpCidr;
 use std::path::PathBuf;
 
 #[derive(Debug, clap::Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 pub struct CliArguments {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[clap(flatten)]
+    pub generate: GenerateArguments,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Generate a new key pair (default)
+    Generate(GenerateArguments),
+    /// Show the client name and public key fingerprint of a private key
+    Inspect(PrivateKeyArguments),
+    /// Check that a private key matches a public key file
+    Verify {
+        #[clap(flatten)]
+        private_key: PrivateKeyArguments,
+        /// Public key file, either an orosu .pub file or OpenSSH public keys
+        #[clap(long)]
+        public_key: PathBuf,
+    },
+    /// Derive the public key from a private key
+    Public {
+        #[clap(flatten)]
+        private_key: PrivateKeyArguments,
+        #[clap(long)]
+        public_key_output: Option<PathBuf>,
+        /// Replace an existing public key file
+        #[clap(long)]
+        force: bool,
+        /// User to own the public key file, e.g. the user orosu-server runs as (Unix only)
+        #[clap(long)]
+        owner: Option<String>,
+    },
+    /// Print the fingerprints of the keys in a public key file
+    Fingerprint {
+        /// Public key file, either an orosu .pub file or OpenSSH public keys
+        public_key: PathBuf,
+    },
+    /// Generate a key pair and add the client to the server configuration
+    Register(RegisterArguments),
+    /// Derive a key that may only run some of the client's scripts
+    Restrict(RestrictArguments),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct GenerateArguments {
     #[clap(short, long)]
     pub name: Option<String>,
     #[clap(long)]
     pub private_key_output: Option<PathBuf>,
     #[clap(long)]
     pub public_key_output: Option<PathBuf>,
+    /// Encrypt the private key with a passphrase read from OROSU_KEY_PASSPHRASE or prompted for
+    #[clap(long)]
+    pub encrypt: bool,
+    /// Replace existing key files
+    #[clap(long)]
+    pub force: bool,
+    /// User to own the public key file, e.g. the user orosu-server runs as (Unix only)
+    #[clap(long)]
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct PrivateKeyArguments {
+    /// Private key file, either generated by orosu-keygen or an OpenSSH ed25519 key
+    pub private_key: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct RegisterArguments {
+    #[clap(flatten)]
+    pub generate: GenerateArguments,
+    /// Configuration file to add the client to. Without it, a configuration snippet is printed
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+    /// Script the client may run, as NAME=COMMAND with shell-style quoting, e.g.
+    /// deploy="bash /etc/orosu/scripts/deploy.sh 'production eu'"
+    #[clap(long = "script", value_parser = parse_script)]
+    pub scripts: Vec<(String, Vec<String>)>,
+    #[clap(long = "whitelisted-ip")]
+    pub whitelisted_ips: Vec<IpCidr>,
+    #[clap(long = "blacklisted-ip")]
+    pub blacklisted_ips: Vec<IpCidr>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct RestrictArguments {
+    #[clap(flatten)]
+    pub private_key: PrivateKeyArguments,
+    /// Script the restricted key may run, repeat for several scripts
+    #[clap(long = "scope", required = true)]
+    pub scopes: Vec<String>,
+    /// Time after which the restricted key is rejected, e.g. 2026-12-31T00:00:00Z
+    #[clap(long)]
+    pub not_after: Option<DateTime<Utc>>,
+    #[clap(long)]
+    pub private_key_output: Option<PathBuf>,
+    /// Encrypt the private key with a passphrase read from OROSU_KEY_PASSPHRASE or prompted for
+    #[clap(long)]
+    pub encrypt: bool,
+    /// Replace an existing private key file
+    #[clap(long)]
+    pub force: bool,
+}
+
+fn parse_script(value: &str) -> Result<(String, Vec<String>), String> {
+    let Some((name, command)) = value.split_once('=') else {
+        return Err(String::from("expected NAME=COMMAND"));
+    };
+    let Some(command) = shlex::split(command) else {
+        return Err(String::from("unbalanced quotes in COMMAND"));
+    };
+    if name.is_empty() || command.is_empty() {
+        return Err(String::from("expected NAME=COMMAND"));
+    }
+    Ok((name.to_string(), command))
 }
@@ -0,0 +1,173 @@
+use orosu::client::Client;
+
+/// Adds `client` to the `clients` list of the configuration in `contents`, or
+/// replaces the entry at `index` if the client is already configured. Only
+/// that entry is rewritten, so comments and formatting of the rest of the
+/// file are kept.
+pub fn upsert_client(
+    contents: &str,
+    client: &Client,
+    index: Option<usize>,
+) -> anyhow::Result<String> {
+    let mut lines: Vec<String> = contents.lines().map(String::from).collect();
+    let Some(clients) = lines.iter().position(|e| is_key(e, "clients")) else {
+        anyhow::bail!("configuration has no clients section");
+    };
+
+    let value = lines[clients]["clients:".len()..].trim();
+    if value.starts_with("[]") && index.is_none() {
+        lines[clients] = String::from("clients:");
+        lines.splice(clients + 1..clients + 1, entry(client, 2)?);
+        return Ok(join(lines, contents));
+    }
+    if !value.is_empty() && !value.starts_with('#') {
+        anyhow::bail!("clients section is not a block list");
+    }
+
+    let block_end = (clients + 1..lines.len())
+        .find(|&i| {
+            lines[i]
+                .chars()
+                .next()
+                .is_some_and(|c| !c.is_whitespace() && c != '#' && c != '-')
+        })
+        .unwrap_or(lines.len());
+    let item_indent = (clients + 1..block_end)
+        .map(|i| &lines[i])
+        .find(|e| e.trim_start().starts_with('-'))
+        .map(|e| indentation(e));
+    let items: Vec<usize> = match item_indent {
+        Some(indent) => (clients + 1..block_end)
+            .filter(|&i| indentation(&lines[i]) == indent && is_item(&lines[i]))
+            .collect(),
+        None => Vec::new(),
+    };
+    let entry = entry(client, item_indent.unwrap_or(2))?;
+
+    match index {
+        Some(index) => {
+            let Some(&start) = items.get(index) else {
+                anyhow::bail!("client {} not found in the clients section", client.name());
+            };
+            let end = content_end(
+                &lines,
+                start,
+                items.get(index + 1).copied().unwrap_or(block_end),
+            );
+            lines.splice(start..end, entry);
+        }
+        None => {
+            let end = content_end(&lines, clients, block_end);
+            lines.splice(end..end, entry);
+        }
+    }
+    Ok(join(lines, contents))
+}
+
+fn is_key(line: &str, key: &str) -> bool {
+    line.strip_prefix(key)
+        .is_some_and(|rest| rest.starts_with(':'))
+}
+
+fn is_item(line: &str) -> bool {
+    let line = line.trim_start();
+    line == "-" || line.starts_with("- ")
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// End of the lines from `start` to `end`, leaving out trailing blank lines
+/// and comments, which usually belong to what follows.
+fn content_end(lines: &[String], start: usize, end: usize) -> usize {
+    let mut end = end;
+    while end > start + 1 {
+        let line = lines[end - 1].trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            break;
+        }
+        end -= 1;
+    }
+    end
+}
+
+/// The client as a list item indented by `indent` spaces.
+fn entry(client: &Client, indent: usize) -> anyhow::Result<Vec<String>> {
+    let yaml = client.to_yaml()?;
+    Ok(yaml
+        .lines()
+        .map(|line| format!("{}{line}", " ".repeat(indent)))
+        .collect())
+}
+
+fn join(lines: Vec<String>, original: &str) -> String {
+    let mut contents = lines.join("\n");
+    if original.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config_file::upsert_client;
+    use orosu::client::Client;
+    use std::path::PathBuf;
+
+    const CONFIGURATION: &str = r#"# Orosu server
+listen:
+  tcp: "0.0.0.0:8081"
+
+clients:
+  # Production deployments
+  - name: "web"
+    secret_file: "/etc/orosu/web.pub"
+    scripts: []
+
+  # Nightly jobs
+  - name: "nightly"
+    secret_file: "/etc/orosu/nightly.pub" # rotated yearly
+    scripts: []
+
+# Keys of leaked secrets
+revocation_file: "/etc/orosu/revoked"
+"#;
+
+    #[test]
+    fn adds_client_and_keeps_comments() {
+        let client = Client::new("db".to_string(), PathBuf::from("/etc/orosu/db.pub"));
+        let contents = upsert_client(CONFIGURATION, &client, None).unwrap();
+        let expected = CONFIGURATION.replace(
+            "    scripts: []\n\n# Keys",
+            "    scripts: []\n  - name: db\n    secret_file: /etc/orosu/db.pub\n    scripts: []\n\n# Keys",
+        );
+        assert_eq!(contents, expected);
+    }
+
+    #[test]
+    fn replaces_only_the_updated_client() {
+        let client = Client::new("web".to_string(), PathBuf::from("/etc/orosu/web2.pub"));
+        let contents = upsert_client(CONFIGURATION, &client, Some(0)).unwrap();
+        let expected = CONFIGURATION.replace(
+            "  - name: \"web\"\n    secret_file: \"/etc/orosu/web.pub\"\n",
+            "  - name: web\n    secret_file: /etc/orosu/web2.pub\n",
+        );
+        assert_eq!(contents, expected);
+    }
+
+    #[test]
+    fn adds_client_to_empty_list() {
+        let client = Client::new("db".to_string(), PathBuf::from("db.pub"));
+        let contents = upsert_client(
+            "listen:\n  tcp: \"0.0.0.0:8081\"\nclients: []\n",
+            &client,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            contents,
+            "listen:\n  tcp: \"0.0.0.0:8081\"\nclients:\n  - name: db\n    secret_file: db.pub\n    scripts: []\n"
+        );
+    }
+}
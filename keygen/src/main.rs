@@ -1,10 +1,26 @@
-use crate::arguments::CliArguments;
+use crate::arguments::{
+    CliArguments, Command, GenerateArguments, PrivateKeyArguments, RegisterArguments,
+    RestrictArguments,
+};
+use crate::output::{
+    Owner, PRIVATE_KEY_MODE, PUBLIC_KEY_MODE, ensure_writable, replace_configuration,
+    resolve_owner, stage_configuration, write_file,
+};
+use anyhow::Context;
 use clap::Parser;
-use orosu::cryptography::Keygen;
+use orosu::client::Client;
+use orosu::configuration::Configuration;
+use orosu::cryptography::{
+    Credential, Keygen, encode_public_key, fingerprint, parse_public_keys, read_passphrase,
+};
+use orosu::script::Script;
 use std::io;
 use std::io::Write;
+use std::path::PathBuf;
 
 mod arguments;
+mod config_file;
+mod output;
 
 fn prompt_input(prompt: &str) -> anyhow::Result<String> {
     print!("{prompt}");
@@ -15,38 +31,307 @@ fn prompt_input(prompt: &str) -> anyhow::Result<String> {
     Ok(input.trim().to_string())
 }
 
-fn main() -> anyhow::Result<()> {
-    let arguments = CliArguments::parse();
+fn read_private_key(arguments: PrivateKeyArguments) -> anyhow::Result<Credential> {
+    let path = arguments.private_key;
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("cannot read private key {}", path.display()))?;
+    Credential::from_string(contents)
+}
+
+fn read_public_key_file(path: &PathBuf) -> anyhow::Result<String> {
+    std::fs::read_to_string(path)
+        .with_context(|| format!("cannot read public key {}", path.display()))
+}
+
+/// A generated key pair, encoded and ready to be written.
+struct KeyPair {
+    keygen: Keygen,
+    private_key: String,
+    public_key: String,
+    owner: Option<Owner>,
+}
+
+/// Generates a key pair, checking beforehand that its files may be written.
+fn generate_keys(arguments: &GenerateArguments) -> anyhow::Result<KeyPair> {
+    for path in [&arguments.private_key_output, &arguments.public_key_output]
+        .into_iter()
+        .flatten()
+    {
+        ensure_writable(path, arguments.force)?;
+    }
+    let owner = arguments.owner.as_deref().map(resolve_owner).transpose()?;
 
-    let name = match arguments.name {
-        Some(name) => name,
+    let name = match &arguments.name {
+        Some(name) => name.clone(),
         None => prompt_input("Name: ")?,
     };
 
     let keygen = Keygen::new(name);
 
-    let private_key = keygen.private_key_base64();
+    let private_key = if arguments.encrypt {
+        keygen.private_key_encrypted(&read_passphrase(true)?)?
+    } else {
+        keygen.private_key_base64()
+    };
     let public_key = keygen.public_key_base64();
 
-    match arguments.private_key_output {
+    Ok(KeyPair {
+        keygen,
+        private_key,
+        public_key,
+        owner,
+    })
+}
+
+/// Writes or prints both keys.
+fn write_keys(arguments: &GenerateArguments, keys: &KeyPair) -> anyhow::Result<()> {
+    match &arguments.private_key_output {
         Some(path) => {
-            std::fs::write(&path, private_key)?;
+            write_file(
+                path,
+                &keys.private_key,
+                PRIVATE_KEY_MODE,
+                arguments.force,
+                None,
+            )?;
             println!("Private key written to {}", path.display());
         }
         None => {
-            println!("Private key: {private_key}");
+            println!("Private key: {}", keys.private_key);
         }
     };
 
-    match arguments.public_key_output {
+    match &arguments.public_key_output {
         Some(path) => {
-            std::fs::write(&path, public_key)?;
+            write_file(
+                path,
+                &keys.public_key,
+                PUBLIC_KEY_MODE,
+                arguments.force,
+                keys.owner.as_ref(),
+            )?;
+            println!("Public key written to {}", path.display());
+        }
+        None => {
+            println!("Public key: {}", keys.public_key);
+        }
+    };
+
+    println!("Fingerprint: {}", keys.keygen.fingerprint());
+    Ok(())
+}
+
+fn generate(arguments: GenerateArguments) -> anyhow::Result<()> {
+    let keys = generate_keys(&arguments)?;
+    write_keys(&arguments, &keys)
+}
+
+/// Generates a key pair and adds the client to the configuration. The updated
+/// configuration is checked before any file is written, so that a rejected
+/// configuration leaves no key files behind.
+fn register(arguments: RegisterArguments) -> anyhow::Result<()> {
+    let generate = arguments.generate;
+    let Some(public_key_output) = generate.public_key_output.clone() else {
+        anyhow::bail!("--public-key-output is required to register a client");
+    };
+
+    let configuration = arguments
+        .config
+        .as_ref()
+        .map(|path| {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("cannot read {}", path.display()))?;
+            Ok::<_, anyhow::Error>((contents, Configuration::from_file(path)?))
+        })
+        .transpose()
+        .context("unable to load configuration file")?;
+
+    let keys = generate_keys(&generate)?;
+    let name = keys.keygen.private_key().client_name.clone();
+
+    let secret_file = std::path::absolute(&public_key_output)
+        .with_context(|| format!("cannot resolve {}", public_key_output.display()))?;
+    let mut client = Client::new(name, secret_file);
+    for (name, command) in arguments.scripts {
+        client.add_script(Script::new(name, command));
+    }
+    if !arguments.whitelisted_ips.is_empty() {
+        client.set_whitelisted_ips(arguments.whitelisted_ips);
+    }
+    if !arguments.blacklisted_ips.is_empty() {
+        client.set_blacklisted_ips(arguments.blacklisted_ips);
+    }
+
+    let (Some(path), Some((contents, mut configuration))) = (arguments.config, configuration)
+    else {
+        write_keys(&generate, &keys)?;
+        let snippet = client.to_yaml()?;
+        println!("Add the client to the clients section of the configuration:\n{snippet}");
+        return Ok(());
+    };
+
+    let client_name = client.name().to_string();
+    let index = configuration
+        .clients
+        .iter()
+        .position(|e| e.name() == client_name);
+    let replaced_keys = index.map_or(0, |i| configuration.clients[i].key_count());
+    configuration.upsert_client(client);
+    let client = &configuration.clients[index.unwrap_or(configuration.clients.len() - 1)];
+    let updated = config_file::upsert_client(&contents, client, index).with_context(|| {
+        format!(
+            "cannot update {}, run without --config to print the client entry",
+            path.display()
+        )
+    })?;
+    let (staged, staged_configuration) = stage_configuration(&path, &updated)?;
+    if !same_clients(&configuration, &staged_configuration)? {
+        anyhow::bail!(
+            "cannot update {} in place, run without --config to print the client entry",
+            path.display()
+        );
+    }
+
+    write_keys(&generate, &keys)?;
+    replace_configuration(&path, staged)?;
+    println!("Client {client_name} registered in {}", path.display());
+    if replaced_keys > 0 {
+        println!(
+            "Replaced {replaced_keys} existing key(s) of {client_name}, only the new key is accepted"
+        );
+    }
+    Ok(())
+}
+
+/// Whether the edited file configures exactly the expected clients.
+fn same_clients(expected: &Configuration, actual: &Configuration) -> anyhow::Result<bool> {
+    let yaml = |configuration: &Configuration| {
+        configuration
+            .clients
+            .iter()
+            .map(Client::to_yaml)
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    Ok(yaml(expected)? == yaml(actual)?)
+}
+
+fn inspect(arguments: PrivateKeyArguments) -> anyhow::Result<()> {
+    let credential = read_private_key(arguments)?;
+    let public_key = credential.key.verifying_key()?;
+    println!("Client name: {}", credential.key.client_name);
+    println!("Fingerprint: {}", fingerprint(&public_key));
+    if let Some(grant) = credential.grant {
+        println!("Scopes: {}", grant.scopes.join(", "));
+        if let Some(not_after) = grant.not_after() {
+            println!("Expires: {}", not_after.to_rfc3339());
+        }
+    }
+    Ok(())
+}
+
+fn restrict(arguments: RestrictArguments) -> anyhow::Result<()> {
+    if let Some(path) = &arguments.private_key_output {
+        ensure_writable(path, arguments.force)?;
+    }
+    let parent = read_private_key(arguments.private_key)?;
+    if parent.grant.is_some() {
+        anyhow::bail!("cannot derive a restricted key from another restricted key");
+    }
+    if parent.key.client_name.is_empty() {
+        anyhow::bail!("client name is missing in the key");
+    }
+
+    let keygen = Keygen::restricted(&parent.key, arguments.scopes, arguments.not_after)?;
+    let private_key = if arguments.encrypt {
+        keygen.private_key_encrypted(&read_passphrase(true)?)?
+    } else {
+        keygen.private_key_base64()
+    };
+
+    match &arguments.private_key_output {
+        Some(path) => {
+            write_file(path, &private_key, PRIVATE_KEY_MODE, arguments.force, None)?;
+            println!("Restricted private key written to {}", path.display());
+        }
+        None => {
+            println!("Restricted private key: {private_key}");
+        }
+    };
+    println!("Fingerprint: {}", keygen.fingerprint());
+    Ok(())
+}
+
+fn verify(private_key: PrivateKeyArguments, public_key: PathBuf) -> anyhow::Result<()> {
+    let key = read_private_key(private_key)?.key.verifying_key()?;
+    let public_keys = parse_public_keys(&read_public_key_file(&public_key)?)
+        .with_context(|| format!("invalid public key {}", public_key.display()))?;
+    if !public_keys.iter().any(|e| e.key == key) {
+        anyhow::bail!(
+            "private key {} does not match {}",
+            fingerprint(&key),
+            public_key.display()
+        );
+    }
+    println!(
+        "Private key {} matches {}",
+        fingerprint(&key),
+        public_key.display()
+    );
+    Ok(())
+}
+
+fn public(
+    private_key: PrivateKeyArguments,
+    public_key_output: Option<PathBuf>,
+    force: bool,
+    owner: Option<String>,
+) -> anyhow::Result<()> {
+    let owner = owner.as_deref().map(resolve_owner).transpose()?;
+    let key = read_private_key(private_key)?.key.verifying_key()?;
+    let public_key = encode_public_key(&key);
+    match public_key_output {
+        Some(path) => {
+            write_file(&path, &public_key, PUBLIC_KEY_MODE, force, owner.as_ref())?;
             println!("Public key written to {}", path.display());
         }
         None => {
             println!("Public key: {public_key}");
         }
     };
+    Ok(())
+}
 
+fn print_fingerprints(public_key: PathBuf) -> anyhow::Result<()> {
+    let public_keys = parse_public_keys(&read_public_key_file(&public_key)?)
+        .with_context(|| format!("invalid public key {}", public_key.display()))?;
+    for public_key in public_keys {
+        match public_key.comment {
+            Some(comment) => println!("{} {comment}", fingerprint(&public_key.key)),
+            None => println!("{}", fingerprint(&public_key.key)),
+        }
+    }
     Ok(())
 }
+
+fn main() -> anyhow::Result<()> {
+    let arguments = CliArguments::parse();
+
+    match arguments.command {
+        None => generate(arguments.generate),
+        Some(Command::Generate(arguments)) => generate(arguments),
+        Some(Command::Inspect(arguments)) => inspect(arguments),
+        Some(Command::Verify {
+            private_key,
+            public_key,
+        }) => verify(private_key, public_key),
+        Some(Command::Public {
+            private_key,
+            public_key_output,
+            force,
+            owner,
+        }) => public(private_key, public_key_output, force, owner),
+        Some(Command::Fingerprint { public_key }) => print_fingerprints(public_key),
+        Some(Command::Register(arguments)) => register(arguments),
+        Some(Command::Restrict(arguments)) => restrict(arguments),
+    }
+}
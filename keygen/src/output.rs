@@ -0,0 +1,145 @@
+use anyhow::Context;
+use orosu::configuration::Configuration;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+pub const PRIVATE_KEY_MODE: u32 = 0o600;
+pub const PUBLIC_KEY_MODE: u32 = 0o644;
+
+/// Fails if `path` exists and may not be overwritten.
+pub fn ensure_writable(path: &Path, force: bool) -> anyhow::Result<()> {
+    if !force && path.exists() {
+        anyhow::bail!(
+            "refusing to overwrite existing {}, pass --force to replace it",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// User and group a written file is handed over to.
+#[cfg_attr(not(unix), allow(dead_code))]
+pub struct Owner {
+    name: String,
+    uid: u32,
+    gid: u32,
+}
+
+/// Looks up `name` so that an unknown user is reported before anything is
+/// written.
+#[cfg(unix)]
+pub fn resolve_owner(name: &str) -> anyhow::Result<Owner> {
+    let user = users::get_user_by_name(name).with_context(|| format!("user {name} not found"))?;
+    Ok(Owner {
+        name: name.to_string(),
+        uid: user.uid(),
+        gid: user.primary_group_id(),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn resolve_owner(name: &str) -> anyhow::Result<Owner> {
+    anyhow::bail!("cannot hand files over to {name}, --owner is only supported on Unix")
+}
+
+/// Atomically writes `contents` to `path` with the given mode: the data goes
+/// to a temporary file in the same directory which is then renamed over
+/// `path`. Existing files are only replaced if `force` is set. If `owner` is
+/// given, the file is handed over to that user and their primary group.
+pub fn write_file(
+    path: &Path,
+    contents: &str,
+    mode: u32,
+    force: bool,
+    owner: Option<&Owner>,
+) -> anyhow::Result<()> {
+    let directory = parent_directory(path);
+    let mut file = NamedTempFile::new_in(directory)
+        .with_context(|| format!("cannot create temporary file in {}", directory.display()))?;
+    #[cfg(unix)]
+    file.as_file()
+        .set_permissions(std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("cannot set permissions of {}", path.display()))?;
+    #[cfg(not(unix))]
+    let _ = mode;
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("cannot write {}", path.display()))?;
+    file.as_file()
+        .sync_all()
+        .with_context(|| format!("cannot write {}", path.display()))?;
+
+    #[cfg(unix)]
+    if let Some(owner) = owner {
+        std::os::unix::fs::chown(file.path(), Some(owner.uid), Some(owner.gid)).with_context(
+            || {
+                format!(
+                    "cannot change owner of {} to {}",
+                    path.display(),
+                    owner.name
+                )
+            },
+        )?;
+    }
+    #[cfg(not(unix))]
+    let _ = owner;
+
+    if force {
+        file.persist(path)
+            .with_context(|| format!("cannot write {}", path.display()))?;
+    } else {
+        file.persist_noclobber(path).map_err(|e| {
+            if e.error.kind() == std::io::ErrorKind::AlreadyExists {
+                anyhow::anyhow!(
+                    "refusing to overwrite existing {}, pass --force to replace it",
+                    path.display()
+                )
+            } else {
+                anyhow::Error::new(e.error).context(format!("cannot write {}", path.display()))
+            }
+        })?;
+    }
+    Ok(())
+}
+
+fn parent_directory(path: &Path) -> &Path {
+    path.parent()
+        .filter(|e| !e.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+/// Writes the new contents of the configuration file at `path` to a
+/// temporary file next to it and checks that they load, so that the file can
+/// be replaced with [`replace_configuration`] once everything else succeeded.
+pub fn stage_configuration(
+    path: &Path,
+    contents: &str,
+) -> anyhow::Result<(NamedTempFile, Configuration)> {
+    let directory = parent_directory(path);
+    let mut file = NamedTempFile::new_in(directory)
+        .with_context(|| format!("cannot create temporary file in {}", directory.display()))?;
+    file.write_all(contents.as_bytes())
+        .context("cannot write configuration")?;
+    let configuration = Configuration::from_file(&file.path().to_path_buf())
+        .context("updated configuration is invalid, leaving the configuration file unchanged")?;
+    Ok((file, configuration))
+}
+
+/// Replaces the configuration file with the staged one, keeping its
+/// permissions. The previous file is kept next to it with a `.bak` extension.
+pub fn replace_configuration(path: &Path, file: NamedTempFile) -> anyhow::Result<()> {
+    let permissions = std::fs::metadata(path)
+        .with_context(|| format!("cannot read {}", path.display()))?
+        .permissions();
+    file.as_file()
+        .set_permissions(permissions)
+        .context("cannot set configuration file permissions")?;
+    let backup = path.with_extension("yaml.bak");
+    std::fs::copy(path, &backup)
+        .with_context(|| format!("cannot back up configuration to {}", backup.display()))?;
+    file.persist(path)
+        .with_context(|| format!("cannot replace {}", path.display()))?;
+    Ok(())
+}
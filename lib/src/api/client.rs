@@ -3,55 +3,135 @@ use crate::api::envelopes::{
     TaskLaunchStatusResponseEnvelope,
 };
 use crate::api::file_chunk::AttachedFiles;
+use crate::api::oidc_token::BoundOidcToken;
+use crate::api::protocol::{PROTOCOL_HEADER, ProtocolRange};
 use crate::api::{
     FileAttachment, ServerErrorResponse, ServerTaskNotification, StartTaskRequest,
     TaskLaunchStatus, UserAgentHeader,
 };
-use crate::cryptography::{Claims, ClientKey};
+use crate::cryptography::{Claims, Credential};
 use crate::server_address::ServerAddress;
 use crate::tasks::TaskOutput;
 use anyhow::Context;
+use axum::http::HeaderMap;
 use axum::http::header::{AUTHORIZATION, USER_AGENT};
 use ed25519_dalek::SigningKey;
 use ed25519_dalek::pkcs8::EncodePrivateKey;
 use futures_util::{SinkExt, StreamExt};
 use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
-use std::process::exit;
+use rustls::RootCertStore;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
-use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+
+/// Lifetime of the tokens issued by the client, in seconds.
+pub const DEFAULT_TOKEN_LIFETIME: u64 = 10;
+
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// Token audience, defaults to the host name of the server address.
+    pub audience: Option<String>,
+    /// Token lifetime in seconds, defaults to [`DEFAULT_TOKEN_LIFETIME`].
+    pub token_lifetime: Option<u64>,
+    /// PEM file with the certificate chain presented to servers that require
+    /// client certificates.
+    pub client_certificate: Option<PathBuf>,
+    /// PEM file with the private key of `client_certificate`.
+    pub client_key: Option<PathBuf>,
+    /// PEM file with the certificate authorities trusted to issue the server
+    /// certificate, replacing the system roots.
+    pub ca_certificates: Option<PathBuf>,
+}
+
+/// How the client proves its identity to the server.
+pub enum Authentication {
+    /// A client key, tokens and task requests are signed with it.
+    Key(Credential),
+    /// An OIDC token issued to the CI workflow, e.g. by GitHub Actions. Task
+    /// requests are not signed, so the server must accept unbound tokens.
+    OidcToken(String),
+    /// An OIDC token bound to a request key, task requests are signed with it.
+    BoundOidcToken(BoundOidcToken),
+}
+
+struct RequestSigner {
+    signing_key: SigningKey,
+    token_id: String,
+}
+
+/// Failure of the connection itself, as opposed to an error the server
+/// reported for the task.
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// The server refused the connection with the HTTP status, e.g. because
+    /// authentication failed.
+    Rejected { status: u16, reason: String },
+    /// The server could not be reached or the connection broke.
+    Failed(String),
+}
+
+impl std::error::Error for ConnectionError {}
+
+impl Display for ConnectionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionError::Rejected { reason, .. } => {
+                write!(f, "Server rejected the connection: {reason}")
+            }
+            ConnectionError::Failed(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+fn connection_failed(e: tungstenite::Error) -> anyhow::Error {
+    ConnectionError::Failed(format!("Connection to the server failed: {e}")).into()
+}
+
+fn unreadable_message(e: serde_json::Error) -> anyhow::Error {
+    ServerErrorResponse::ProtocolMismatch {
+        message: Some(format!("cannot read server message: {e}")),
+    }
+    .into()
+}
 
 pub struct ApiClient {
     ws_stream: Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    signer: Option<RequestSigner>,
 }
 
 impl ApiClient {
-    pub async fn connect(endpoint: ServerAddress, key: ClientKey) -> anyhow::Result<Self> {
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_secs() as usize;
-
-        let encoding_key = {
-            let secret_key = key
-                .key
-                .as_slice()
-                .try_into()
-                .context("invalid key format")?;
-            let signing_key = SigningKey::from_bytes(secret_key)
-                .to_pkcs8_der()
-                .context("unable to encode signing key")?;
-            EncodingKey::from_ed_der(signing_key.as_bytes())
-        };
-        let header = Header::new(Algorithm::EdDSA);
-
-        let claims = Claims {
-            sub: key.client_name,
-            exp: now + 10, // 10-second expiration
+    pub async fn connect(
+        endpoint: ServerAddress,
+        authentication: Authentication,
+        options: ConnectOptions,
+    ) -> anyhow::Result<Self> {
+        let connector = Self::tls_connector(&options)?;
+        let (authorization, signer) = match authentication {
+            Authentication::Key(credential) => {
+                let audience = options.audience.unwrap_or_else(|| endpoint.audience());
+                let lifetime = options.token_lifetime.unwrap_or(DEFAULT_TOKEN_LIFETIME);
+                let (token, signer) = Self::key_token(credential, audience, lifetime)?;
+                (format!("Token {token}"), Some(signer))
+            }
+            Authentication::OidcToken(token) => (format!("Bearer {token}"), None),
+            Authentication::BoundOidcToken(token) => {
+                let signer = RequestSigner {
+                    token_id: token.token_id()?,
+                    signing_key: token.request_key,
+                };
+                (format!("Bearer {}", token.token), Some(signer))
+            }
         };
-        let token = encode(&header, &claims, &encoding_key).context("cannot encode JWT")?;
 
         let user_agent_header = UserAgentHeader::default();
         let mut request = endpoint
@@ -60,25 +140,163 @@ impl ApiClient {
             .context("Cannot create request")?;
         request
             .headers_mut()
-            .insert(AUTHORIZATION, format!("Token {token}").parse()?);
+            .insert(AUTHORIZATION, authorization.parse()?);
         request
             .headers_mut()
             .insert(USER_AGENT, user_agent_header.into());
 
-        let (ws_stream, _) = tokio_tungstenite::connect_async(request)
-            .await
-            .context("Cannot connect")?;
+        let connection =
+            tokio_tungstenite::connect_async_tls_with_config(request, None, false, connector);
+        let (ws_stream, response) = match connection.await {
+            Ok(connection) => connection,
+            Err(tungstenite::Error::Http(response)) => {
+                // Proxies in front of the server answer without the header.
+                if response.headers().contains_key(PROTOCOL_HEADER) {
+                    Self::negotiate_protocol(response.headers())?;
+                }
+                let reason = response
+                    .body()
+                    .as_deref()
+                    .map(String::from_utf8_lossy)
+                    .filter(|e| !e.is_empty())
+                    .unwrap_or_default();
+                return Err(ConnectionError::Rejected {
+                    status: response.status().as_u16(),
+                    reason: format!("{} {reason}", response.status()),
+                }
+                .into());
+            }
+            Err(e) => return Err(ConnectionError::Failed(format!("Cannot connect: {e}")).into()),
+        };
+        let protocol = Self::negotiate_protocol(response.headers())?;
+        tracing::debug!("Speaking protocol version {protocol}");
         let ws_stream = Mutex::new(ws_stream);
-        Ok(Self { ws_stream })
+        Ok(Self { ws_stream, signer })
     }
 
+    /// The protocol version to speak with the server, given the versions it
+    /// advertises. Servers that advertise none predate protocol versioning
+    /// and are refused, as they reject the tokens of this client.
+    fn negotiate_protocol(headers: &HeaderMap) -> anyhow::Result<u32> {
+        let Some(server) = headers.get(PROTOCOL_HEADER) else {
+            return Err(ServerErrorResponse::ProtocolMismatch {
+                message: Some(String::from(
+                    "server was released before protocol versioning; upgrade orosu-server",
+                )),
+            }
+            .into());
+        };
+        let server: ProtocolRange = server
+            .to_str()
+            .map_err(anyhow::Error::from)
+            .and_then(str::parse)
+            .context("Server advertised an invalid protocol version")?;
+        let client = ProtocolRange::supported();
+        client.negotiate(server).ok_or_else(|| {
+            let upgrade = if server.min > client.max {
+                "upgrade orosu-client"
+            } else {
+                "upgrade orosu-server"
+            };
+            ServerErrorResponse::ProtocolMismatch {
+                message: Some(format!(
+                    "server speaks protocol versions {server}, client speaks {client}; {upgrade}"
+                )),
+            }
+            .into()
+        })
+    }
+
+    /// TLS settings for client certificates or custom certificate
+    /// authorities, `None` to use the defaults.
+    fn tls_connector(options: &ConnectOptions) -> anyhow::Result<Option<Connector>> {
+        if options.client_certificate.is_none() && options.ca_certificates.is_none() {
+            return Ok(None);
+        }
+
+        let mut roots = RootCertStore::empty();
+        match &options.ca_certificates {
+            Some(path) => {
+                for certificate in read_certificates(path)? {
+                    roots
+                        .add(certificate)
+                        .with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+                }
+            }
+            None => {
+                let native = rustls_native_certs::load_native_certs();
+                for e in native.errors {
+                    tracing::warn!("Cannot load system root certificate: {e}");
+                }
+                roots.add_parsable_certificates(native.certs);
+            }
+        }
+
+        let builder = rustls::ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .context("Failed to configure TLS")?
+            .with_root_certificates(roots);
+        let config = match (&options.client_certificate, &options.client_key) {
+            (Some(certificate), Some(key)) => {
+                let chain = read_certificates(certificate)?;
+                let key = PrivateKeyDer::from_pem_file(key)
+                    .with_context(|| format!("Failed to read client key {}", key.display()))?;
+                builder
+                    .with_client_auth_cert(chain, key)
+                    .context("Client key does not match the client certificate")?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => anyhow::bail!("Client certificate and client key must be set together"),
+        };
+        Ok(Some(Connector::Rustls(Arc::new(config))))
+    }
+
+    fn key_token(
+        credential: Credential,
+        audience: String,
+        lifetime: u64,
+    ) -> anyhow::Result<(String, RequestSigner)> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs() as usize;
+
+        let key = credential.key;
+        let signing_key = SigningKey::from_bytes(
+            key.key
+                .as_slice()
+                .try_into()
+                .context("invalid key format")?,
+        );
+        let encoding_key = {
+            let der = signing_key
+                .to_pkcs8_der()
+                .context("unable to encode signing key")?;
+            EncodingKey::from_ed_der(der.as_bytes())
+        };
+        let header = Header::new(Algorithm::EdDSA);
+
+        let mut claims = Claims::new(key.client_name, audience, now, lifetime as usize);
+        if let Some(grant) = &credential.grant {
+            claims = claims.with_grant(grant)?;
+        }
+        let token = encode(&header, &claims, &encoding_key).context("cannot encode JWT")?;
+        let signer = RequestSigner {
+            signing_key,
+            token_id: claims.jti,
+        };
+        Ok((token, signer))
+    }
+
+    /// Runs the script and returns its exit code. Errors reported by the
+    /// server are returned as [`ServerErrorResponse`] and connection
+    /// problems as [`ConnectionError`].
     pub async fn start_task(
         &self,
         arguments: Vec<String>,
         script_name: String,
         files: Vec<String>,
         chunk_size: usize,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<i32> {
         let file_chunks = if !files.is_empty() {
             let archive = AttachedFiles::from_input(files);
             Some(archive.chunks(chunk_size)?)
@@ -90,33 +308,45 @@ impl ApiClient {
 
         let mut ws_stream = self.ws_stream.lock().await;
 
-        let start_task_request = TaskLaunchRequestEnvelope {
-            body: StartTaskRequest {
-                script_name,
-                arguments,
-                file,
+        let body = StartTaskRequest {
+            script_name,
+            arguments,
+            file,
+        };
+        let start_task_request = match &self.signer {
+            Some(signer) => {
+                TaskLaunchRequestEnvelope::signed(body, &signer.token_id, &signer.signing_key)
+            }
+            None => TaskLaunchRequestEnvelope {
+                body,
+                signature: None,
             },
         };
         ws_stream
             .send(Message::Binary(start_task_request.into()))
-            .await?;
+            .await
+            .map_err(connection_failed)?;
 
         loop {
             let response = ws_stream.next().await;
             let Some(response) = response else {
-                anyhow::bail!("Server did not respond")
+                return Err(ConnectionError::Failed(String::from("Server did not respond")).into());
             };
-            let response = response?;
+            let response = response.map_err(connection_failed)?;
             let Message::Binary(response_bytes) = response else {
-                anyhow::bail!("Server did not respond with a valid response, got {response}")
+                return Err(ConnectionError::Failed(format!(
+                    "Server did not respond with a valid response, got {response}"
+                ))
+                .into());
             };
-            let response: TaskLaunchStatusResponseEnvelope = response_bytes.into();
+            let response = TaskLaunchStatusResponseEnvelope::try_from(response_bytes)
+                .map_err(unreadable_message)?;
             match response {
                 TaskLaunchStatusResponseEnvelope::Success { body, .. } => match body {
                     TaskLaunchStatus::AwaitingFiles { offset, .. } => {
                         match file_chunks.as_ref() {
                             None => {
-                                ws_stream.send(Message::Close(None)).await?;
+                                _ = ws_stream.send(Message::Close(None)).await;
                                 anyhow::bail!("No files were attached to the task");
                             }
                             Some(chunks) => {
@@ -124,12 +354,14 @@ impl ApiClient {
                                 if let Some(chunk) = chunk {
                                     let file_chunk_envelope = FileChunkRequestEnvelope {
                                         body: chunk.clone(),
+                                        signature: None,
                                     };
                                     ws_stream
                                         .send(Message::Binary(file_chunk_envelope.into()))
-                                        .await?;
+                                        .await
+                                        .map_err(connection_failed)?;
                                 } else {
-                                    ws_stream.send(Message::Close(None)).await?;
+                                    _ = ws_stream.send(Message::Close(None)).await;
                                     anyhow::bail!("Chunk not found for offset {offset}");
                                 }
                             }
@@ -137,28 +369,29 @@ impl ApiClient {
                     }
                     TaskLaunchStatus::Launched { .. } => break,
                 },
-                TaskLaunchStatusResponseEnvelope::Failure { error, .. } => error.panic(),
+                TaskLaunchStatusResponseEnvelope::Failure { error, .. } => return Err(error.into()),
             }
         }
 
         while let Some(event) = ws_stream.next().await {
-            let event = event?;
+            let event = event.map_err(connection_failed)?;
             match event {
                 Message::Binary(event) => {
-                    let event: TaskEventResponseEnvelope = event.into();
+                    let event =
+                        TaskEventResponseEnvelope::try_from(event).map_err(unreadable_message)?;
                     match event {
                         TaskEventResponseEnvelope::Success { body, .. } => match body {
                             ServerTaskNotification::Output(output) => {
                                 output.value.print();
                             }
                             ServerTaskNotification::ExitCode(exit_code) => {
-                                ws_stream.send(Message::Close(None)).await?;
-                                exit(exit_code);
+                                _ = ws_stream.send(Message::Close(None)).await;
+                                return Ok(exit_code);
                             }
                         },
                         TaskEventResponseEnvelope::Failure { error, .. } => {
-                            ws_stream.send(Message::Close(None)).await?;
-                            error.panic();
+                            _ = ws_stream.send(Message::Close(None)).await;
+                            return Err(error.into());
                         }
                     }
                 }
@@ -167,12 +400,15 @@ impl ApiClient {
                     break;
                 }
                 _ => {
-                    panic!("Unexpected message: {event:?}");
+                    tracing::debug!("Ignoring unexpected message: {event:?}");
                 }
             }
         }
 
-        Ok(())
+        Err(ConnectionError::Failed(String::from(
+            "Connection was closed before the script finished",
+        ))
+        .into())
     }
 }
 
@@ -186,11 +422,43 @@ impl TaskOutput {
 }
 
 impl ServerErrorResponse {
-    fn panic(&self) {
+    fn summary(&self) -> &'static str {
         match self {
-            ServerErrorResponse::CannotLaunchScript => panic!("Cannot launch script"),
-            ServerErrorResponse::ScriptNotFound => panic!("Script not found"),
-            ServerErrorResponse::Unknown => panic!("Unknown error"),
+            ServerErrorResponse::CannotLaunchScript { .. } => "Cannot launch script",
+            ServerErrorResponse::ScriptNotFound { .. } => "Script not found",
+            ServerErrorResponse::InvalidSignature { .. } => "Request signature was rejected",
+            ServerErrorResponse::Forbidden { .. } => "Script is not allowed for this connection",
+            ServerErrorResponse::InvalidRequest { .. } => "Request was rejected as malformed",
+            ServerErrorResponse::AttachmentTooLarge { .. } => {
+                "Attachment exceeds the size limits of the script"
+            }
+            ServerErrorResponse::InvalidAttachment { .. } => "Attachment was rejected as invalid",
+            ServerErrorResponse::HashMismatch { .. } => "Attachment was corrupted in transfer",
+            ServerErrorResponse::ProtocolMismatch { .. } => {
+                "Client and server protocol versions are incompatible"
+            }
+            ServerErrorResponse::Unknown { .. } => "Unknown error",
         }
     }
 }
+
+impl std::error::Error for ServerErrorResponse {}
+
+impl Display for ServerErrorResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.message() {
+            Some(message) => write!(f, "{}: {message}", self.summary()),
+            None => write!(f, "{}", self.summary()),
+        }
+    }
+}
+
+fn read_certificates(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates {}", path.display()))?;
+    if certificates.is_empty() {
+        anyhow::bail!("No certificate found in {}", path.display());
+    }
+    Ok(certificates)
+}
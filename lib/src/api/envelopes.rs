@@ -1,4 +1,5 @@
 use crate::api::file_chunk::FileChunk;
+use crate::api::protocol::{LegacyServerErrorResponse, PROTOCOL_VERSION};
 use crate::api::{ServerErrorResponse, ServerTaskNotification, StartTaskRequest, TaskLaunchStatus};
 use crate::tasks::{TaskOutput, Timestamped};
 use bytes::Bytes;
@@ -9,6 +10,8 @@ use serde::{Deserialize, Serialize};
 pub struct RequestEnvelope<T> {
     #[serde(rename = "body")]
     pub body: T,
+    #[serde(rename = "signature", default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Vec<u8>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -44,22 +47,63 @@ where
     }
 }
 
-impl<T> From<Bytes> for RequestEnvelope<T>
+impl<T> TryFrom<Bytes> for RequestEnvelope<T>
 where
     T: DeserializeOwned,
 {
-    fn from(value: Bytes) -> Self {
-        serde_json::from_slice(&value).unwrap()
+    type Error = serde_json::Error;
+
+    fn try_from(value: Bytes) -> Result<Self, Self::Error> {
+        serde_json::from_slice(&value)
     }
 }
 
-impl<T, E> From<Bytes> for ResponseEnvelope<T, E>
+impl<T, E> TryFrom<Bytes> for ResponseEnvelope<T, E>
 where
     T: DeserializeOwned,
     E: DeserializeOwned,
 {
-    fn from(value: Bytes) -> Self {
-        serde_json::from_slice(&value).unwrap()
+    type Error = serde_json::Error;
+
+    fn try_from(value: Bytes) -> Result<Self, Self::Error> {
+        serde_json::from_slice(&value)
+    }
+}
+
+impl<T> ResponseEnvelope<T, ServerErrorResponse>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Serializes the response in the message format of protocol `version`.
+    pub fn encode(self, version: u32) -> Bytes {
+        if version >= PROTOCOL_VERSION {
+            return self.into();
+        }
+        match self {
+            ResponseEnvelope::Success { body } => {
+                ResponseEnvelope::<T, LegacyServerErrorResponse>::Success { body }.into()
+            }
+            ResponseEnvelope::Failure { error } => {
+                ResponseEnvelope::<T, LegacyServerErrorResponse>::Failure {
+                    error: error.into(),
+                }
+                .into()
+            }
+        }
+    }
+
+    /// Parses a response in the message format of protocol `version`.
+    pub fn decode(value: Bytes, version: u32) -> Result<Self, serde_json::Error> {
+        if version >= PROTOCOL_VERSION {
+            return value.try_into();
+        }
+        let response: ResponseEnvelope<T, LegacyServerErrorResponse> = value.try_into()?;
+        Ok(match response {
+            ResponseEnvelope::Success { body } => ResponseEnvelope::Success { body },
+            ResponseEnvelope::Failure { error } => ResponseEnvelope::Failure {
+                error: error.into(),
+            },
+        })
     }
 }
 
@@ -68,3 +112,60 @@ pub type TaskLaunchStatusResponseEnvelope = ResponseEnvelope<TaskLaunchStatus, S
 pub type TaskEventResponseEnvelope =
     ResponseEnvelope<ServerTaskNotification<Timestamped<TaskOutput>, i32>, ServerErrorResponse>;
 pub type TaskLaunchRequestEnvelope = RequestEnvelope<StartTaskRequest>;
+
+#[cfg(test)]
+mod tests {
+    use crate::api::envelopes::TaskLaunchStatusResponseEnvelope;
+    use crate::api::{ServerErrorResponse, TaskLaunchStatus};
+    use bytes::Bytes;
+
+    fn forbidden() -> TaskLaunchStatusResponseEnvelope {
+        TaskLaunchStatusResponseEnvelope::Failure {
+            error: ServerErrorResponse::Forbidden {
+                message: Some(String::from(
+                    "script deploy cannot be run from this address",
+                )),
+            },
+        }
+    }
+
+    #[test]
+    fn encodes_errors_for_each_protocol_version() {
+        let current = forbidden().encode(2);
+        assert_eq!(
+            current,
+            Bytes::from_static(
+                br#"{"failure":{"error":{"forbidden":{"message":"script deploy cannot be run from this address"}}}}"#
+            )
+        );
+        let decoded = TaskLaunchStatusResponseEnvelope::decode(current, 2).unwrap();
+        assert!(matches!(
+            decoded,
+            TaskLaunchStatusResponseEnvelope::Failure {
+                error: ServerErrorResponse::Forbidden { message: Some(_) }
+            }
+        ));
+
+        let legacy = forbidden().encode(1);
+        assert_eq!(
+            legacy,
+            Bytes::from_static(br#"{"failure":{"error":"cannot_launch_script"}}"#)
+        );
+        let decoded = TaskLaunchStatusResponseEnvelope::decode(legacy, 1).unwrap();
+        assert!(matches!(
+            decoded,
+            TaskLaunchStatusResponseEnvelope::Failure {
+                error: ServerErrorResponse::CannotLaunchScript { message: None }
+            }
+        ));
+
+        let launched = TaskLaunchStatusResponseEnvelope::Success {
+            body: TaskLaunchStatus::AwaitingFiles { offset: 0 },
+        };
+        assert_eq!(
+            launched.encode(1),
+            Bytes::from_static(br#"{"success":{"body":{"awaiting_files":{"offset":0}}}}"#)
+        );
+        assert!(TaskLaunchStatusResponseEnvelope::decode(Bytes::from_static(b"{}"), 2).is_err());
+    }
+}
@@ -1,6 +1,8 @@
 use crate::api::FileAttachment;
+use crate::api::protocol::PROTOCOL_VERSION;
 use anyhow::Context;
 use glob::glob;
+use sha2::{Digest, Sha256};
 use std::fs::File;
 use std::io::{Read, Seek, Write};
 use std::path::PathBuf;
@@ -17,6 +19,36 @@ pub struct AttachedFiles {
     paths: Vec<PathBuf>,
 }
 
+/// Digest of an attachment archive: SHA-256, or MD5 in protocol version 1.
+pub enum AttachmentDigest {
+    Md5(md5::Context),
+    Sha256(Sha256),
+}
+
+impl AttachmentDigest {
+    pub fn new(protocol: u32) -> Self {
+        if protocol >= PROTOCOL_VERSION {
+            AttachmentDigest::Sha256(Sha256::new())
+        } else {
+            AttachmentDigest::Md5(md5::Context::new())
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            AttachmentDigest::Md5(context) => context.consume(data),
+            AttachmentDigest::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            AttachmentDigest::Md5(context) => context.finalize().to_vec(),
+            AttachmentDigest::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
 pub struct FileChunkResult {
     pub chunks: Vec<FileChunk>,
     pub hash: Vec<u8>,
@@ -85,8 +117,7 @@ impl AttachedFiles {
             .read_to_end(&mut buffer)
             .context("cannot read archive file")?;
 
-        let hasher = md5::compute(buffer.as_slice());
-        let hash = hasher.0.to_vec();
+        let hash = Sha256::digest(buffer.as_slice()).to_vec();
 
         let mut chunks = Vec::new();
         let mut offset = 0;
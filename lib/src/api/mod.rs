@@ -1,8 +1,12 @@
 pub mod client;
 pub mod envelopes;
 pub mod file_chunk;
+pub mod oidc_token;
+pub mod protocol;
+mod request_signature;
 mod user_agent_header;
 
+use crate::api::protocol::ProtocolRange;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
@@ -18,6 +22,7 @@ pub struct StartTaskRequest {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FileAttachment {
+    /// SHA-256 digest of the archive, covered by the request signature.
     #[serde(rename = "hash")]
     pub hash: Vec<u8>,
     #[serde(rename = "size")]
@@ -32,14 +37,77 @@ pub enum TaskLaunchStatus {
     Launched { started_on: DateTime<Utc> },
 }
 
+/// Reason the server refused or aborted a task. The message explains the
+/// specific failure and is printed by the client.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServerErrorResponse {
     #[serde(rename = "cannot_launch_script")]
-    CannotLaunchScript,
+    CannotLaunchScript {
+        #[serde(rename = "message", default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
     #[serde(rename = "script_not_found")]
-    ScriptNotFound,
+    ScriptNotFound {
+        #[serde(rename = "message", default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    #[serde(rename = "invalid_signature")]
+    InvalidSignature {
+        #[serde(rename = "message", default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    #[serde(rename = "forbidden")]
+    Forbidden {
+        #[serde(rename = "message", default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    #[serde(rename = "invalid_request")]
+    InvalidRequest {
+        #[serde(rename = "message", default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    #[serde(rename = "attachment_too_large")]
+    AttachmentTooLarge {
+        #[serde(rename = "message", default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    #[serde(rename = "invalid_attachment")]
+    InvalidAttachment {
+        #[serde(rename = "message", default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    #[serde(rename = "hash_mismatch")]
+    HashMismatch {
+        #[serde(rename = "message", default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    #[serde(rename = "protocol_mismatch")]
+    ProtocolMismatch {
+        #[serde(rename = "message", default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
     #[serde(rename = "unknown")]
-    Unknown,
+    Unknown {
+        #[serde(rename = "message", default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+}
+
+impl ServerErrorResponse {
+    pub fn message(&self) -> Option<&str> {
+        match self {
+            ServerErrorResponse::CannotLaunchScript { message }
+            | ServerErrorResponse::ScriptNotFound { message }
+            | ServerErrorResponse::InvalidSignature { message }
+            | ServerErrorResponse::Forbidden { message }
+            | ServerErrorResponse::InvalidRequest { message }
+            | ServerErrorResponse::AttachmentTooLarge { message }
+            | ServerErrorResponse::InvalidAttachment { message }
+            | ServerErrorResponse::HashMismatch { message }
+            | ServerErrorResponse::ProtocolMismatch { message }
+            | ServerErrorResponse::Unknown { message } => message.as_deref(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -52,4 +120,6 @@ pub enum ServerTaskNotification<O, E> {
 
 pub struct UserAgentHeader {
     pub version: String,
+    /// Protocol versions the client speaks.
+    pub protocol: ProtocolRange,
 }
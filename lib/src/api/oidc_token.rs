@@ -0,0 +1,118 @@
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ed25519_dalek::ed25519::signature::rand_core::OsRng;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use serde::Deserialize;
+
+/// Separates the configured audience from the request key in the audience of
+/// a bound OIDC token.
+const REQUEST_KEY_SEPARATOR: char = ':';
+
+const GITHUB_TOKEN_URL_ENV: &str = "ACTIONS_ID_TOKEN_REQUEST_URL";
+const GITHUB_TOKEN_ENV: &str = "ACTIONS_ID_TOKEN_REQUEST_TOKEN";
+
+/// Audience of an OIDC token bound to `key`, e.g. `orosu:<key>`. Task requests
+/// sent with the token must be signed with the key. The identity provider
+/// signs the audience, so whoever relays the token cannot substitute a key
+/// of their own.
+pub fn bound_audience(audience: &str, key: &VerifyingKey) -> String {
+    format!(
+        "{audience}{REQUEST_KEY_SEPARATOR}{}",
+        URL_SAFE_NO_PAD.encode(key.as_bytes())
+    )
+}
+
+/// Matches the audience of a token against the configured `audience`:
+/// `Some(None)` if it is the plain audience, `Some(Some(key))` if it is bound
+/// to a request key and `None` if it does not match.
+pub(crate) fn parse_audience(value: &str, audience: &str) -> Option<Option<VerifyingKey>> {
+    if value == audience {
+        return Some(None);
+    }
+    let key = value
+        .strip_prefix(audience)?
+        .strip_prefix(REQUEST_KEY_SEPARATOR)?;
+    let key: [u8; 32] = URL_SAFE_NO_PAD.decode(key).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&key).ok().map(Some)
+}
+
+#[derive(Deserialize)]
+struct GithubTokenResponse {
+    #[serde(rename = "value")]
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct TokenId {
+    #[serde(rename = "jti")]
+    jti: String,
+}
+
+/// An OIDC token bound to a request key generated for this connection.
+pub struct BoundOidcToken {
+    pub token: String,
+    pub request_key: SigningKey,
+}
+
+impl BoundOidcToken {
+    /// Requests a GitHub Actions OIDC token bound to a new request key. The
+    /// job needs the `id-token: write` permission.
+    pub async fn request_github(audience: &str) -> anyhow::Result<Self> {
+        let url = std::env::var(GITHUB_TOKEN_URL_ENV).with_context(|| {
+            format!(
+                "{GITHUB_TOKEN_URL_ENV} is not set, grant the job the id-token: write permission"
+            )
+        })?;
+        let bearer = std::env::var(GITHUB_TOKEN_ENV)
+            .with_context(|| format!("{GITHUB_TOKEN_ENV} is not set"))?;
+        let request_key = SigningKey::generate(&mut OsRng);
+        let mut url = reqwest::Url::parse(&url).context("Invalid OIDC token request URL")?;
+        url.query_pairs_mut().append_pair(
+            "audience",
+            &bound_audience(audience, &request_key.verifying_key()),
+        );
+
+        // reqwest is built without a crypto provider of its own, use the ring
+        // provider rustls is compiled with. Fails harmlessly once installed.
+        _ = rustls::crypto::ring::default_provider().install_default();
+        let response: GithubTokenResponse = reqwest::Client::new()
+            .get(url)
+            .bearer_auth(bearer)
+            .send()
+            .await
+            .and_then(|e| e.error_for_status())
+            .context("Cannot request an OIDC token")?
+            .json()
+            .await
+            .context("Invalid OIDC token response")?;
+        Ok(Self {
+            token: response.value,
+            request_key,
+        })
+    }
+
+    /// Identifier of the token, to which signed requests are bound.
+    pub(crate) fn token_id(&self) -> anyhow::Result<String> {
+        let token_id = jsonwebtoken::dangerous::insecure_decode::<TokenId>(&self.token)
+            .context("OIDC token has no jti claim")?;
+        Ok(token_id.claims.jti)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::oidc_token::{bound_audience, parse_audience};
+    use ed25519_dalek::SigningKey;
+
+    #[test]
+    fn binds_audience_to_request_key() {
+        let key = SigningKey::from_bytes(&[7; 32]).verifying_key();
+        let audience = bound_audience("orosu", &key);
+        assert_eq!(parse_audience(&audience, "orosu"), Some(Some(key)));
+        assert_eq!(parse_audience("orosu", "orosu"), Some(None));
+        assert_eq!(parse_audience(&audience, "other"), None);
+        assert_eq!(parse_audience("orosu:invalid", "orosu"), None);
+        assert_eq!(parse_audience("orosu-staging", "orosu"), None);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::api::ServerErrorResponse;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// Version of the message format spoken by this build. Version 2 added
+/// messages to the server errors.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest version still spoken, for clients and servers that do not
+/// announce a version. Version 1 is deprecated: its clients do not sign
+/// their requests.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Response header in which the server advertises its supported versions.
+pub const PROTOCOL_HEADER: &str = "orosu-protocol";
+
+/// Inclusive range of protocol versions, written as `1-2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl ProtocolRange {
+    /// Versions supported by this build.
+    pub const fn supported() -> Self {
+        Self {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        }
+    }
+
+    /// Versions of a peer that does not announce any, i.e. of a release
+    /// before protocol versioning.
+    pub const fn unversioned() -> Self {
+        Self {
+            min: MIN_PROTOCOL_VERSION,
+            max: MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    /// The version to speak with a peer: the newest one both sides support.
+    pub fn negotiate(&self, peer: ProtocolRange) -> Option<u32> {
+        let version = self.max.min(peer.max);
+        (version >= self.min && version >= peer.min).then_some(version)
+    }
+}
+
+impl Display for ProtocolRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.min, self.max)
+    }
+}
+
+impl FromStr for ProtocolRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (min, max) = s.trim().split_once('-').unwrap_or((s, s));
+        let range = Self {
+            min: min.trim().parse()?,
+            max: max.trim().parse()?,
+        };
+        if range.min > range.max {
+            anyhow::bail!("Invalid protocol range {s}");
+        }
+        Ok(range)
+    }
+}
+
+/// Server errors of protocol version 1, which carry no message. Errors that
+/// did not exist then are reported as the closest one.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum LegacyServerErrorResponse {
+    #[serde(rename = "cannot_launch_script")]
+    CannotLaunchScript,
+    #[serde(rename = "script_not_found")]
+    ScriptNotFound,
+    #[serde(rename = "unknown")]
+    Unknown,
+}
+
+impl From<ServerErrorResponse> for LegacyServerErrorResponse {
+    fn from(value: ServerErrorResponse) -> Self {
+        match value {
+            ServerErrorResponse::ScriptNotFound { .. } => LegacyServerErrorResponse::ScriptNotFound,
+            ServerErrorResponse::Unknown { .. } => LegacyServerErrorResponse::Unknown,
+            _ => LegacyServerErrorResponse::CannotLaunchScript,
+        }
+    }
+}
+
+impl From<LegacyServerErrorResponse> for ServerErrorResponse {
+    fn from(value: LegacyServerErrorResponse) -> Self {
+        match value {
+            LegacyServerErrorResponse::CannotLaunchScript => {
+                ServerErrorResponse::CannotLaunchScript { message: None }
+            }
+            LegacyServerErrorResponse::ScriptNotFound => {
+                ServerErrorResponse::ScriptNotFound { message: None }
+            }
+            LegacyServerErrorResponse::Unknown => ServerErrorResponse::Unknown { message: None },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::protocol::ProtocolRange;
+
+    #[test]
+    fn negotiates_newest_common_version() {
+        let range = |value: &str| value.parse::<ProtocolRange>().unwrap();
+        let server = range("1-2");
+        assert_eq!(server.negotiate(range("1-2")), Some(2));
+        assert_eq!(server.negotiate(ProtocolRange::unversioned()), Some(1));
+        assert_eq!(server.negotiate(range("2-3")), Some(2));
+        assert_eq!(server.negotiate(range("3-4")), None);
+        assert_eq!(range("3-4").negotiate(server), None);
+        assert_eq!("2".parse::<ProtocolRange>().unwrap().to_string(), "2-2");
+        assert!("2-1".parse::<ProtocolRange>().is_err());
+    }
+}
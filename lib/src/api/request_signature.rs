@@ -0,0 +1,98 @@
+use crate::api::StartTaskRequest;
+use crate::api::envelopes::TaskLaunchRequestEnvelope;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+const SIGNATURE_CONTEXT: &[u8] = b"orosu-task-request-v1";
+
+fn append_field(payload: &mut Vec<u8>, field: &[u8]) {
+    payload.extend_from_slice(&(field.len() as u64).to_be_bytes());
+    payload.extend_from_slice(field);
+}
+
+impl StartTaskRequest {
+    /// Canonical byte representation of the request, bound to the identifier
+    /// of the token that authenticated the connection so that a signed request
+    /// cannot be replayed over another connection.
+    fn signing_payload(&self, token_id: &str) -> Vec<u8> {
+        let mut payload = Vec::new();
+        append_field(&mut payload, SIGNATURE_CONTEXT);
+        append_field(&mut payload, token_id.as_bytes());
+        append_field(&mut payload, self.script_name.as_bytes());
+        payload.extend_from_slice(&(self.arguments.len() as u64).to_be_bytes());
+        for argument in &self.arguments {
+            append_field(&mut payload, argument.as_bytes());
+        }
+        match &self.file {
+            None => payload.push(0),
+            Some(file) => {
+                payload.push(1);
+                append_field(&mut payload, &file.hash);
+                payload.extend_from_slice(&(file.size as u64).to_be_bytes());
+            }
+        }
+        payload
+    }
+}
+
+impl TaskLaunchRequestEnvelope {
+    pub fn signed(body: StartTaskRequest, token_id: &str, key: &SigningKey) -> Self {
+        let signature = key.sign(&body.signing_payload(token_id));
+        Self {
+            body,
+            signature: Some(signature.to_vec()),
+        }
+    }
+
+    pub fn verify(&self, token_id: &str, key: &VerifyingKey) -> bool {
+        let Some(signature) = &self.signature else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_slice(signature) else {
+            return false;
+        };
+        key.verify(&self.body.signing_payload(token_id), &signature)
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::envelopes::TaskLaunchRequestEnvelope;
+    use crate::api::{FileAttachment, StartTaskRequest};
+    use ed25519_dalek::SigningKey;
+
+    fn request() -> StartTaskRequest {
+        StartTaskRequest {
+            script_name: "deploy".to_string(),
+            arguments: vec!["a".to_string(), "b".to_string()],
+            file: Some(FileAttachment {
+                hash: vec![1, 2, 3],
+                size: 3,
+            }),
+        }
+    }
+
+    #[test]
+    fn signed_request_is_verified() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let envelope = TaskLaunchRequestEnvelope::signed(request(), "token", &key);
+        assert!(envelope.verify("token", &key.verifying_key()));
+        assert!(!envelope.verify("other-token", &key.verifying_key()));
+    }
+
+    #[test]
+    fn tampered_request_is_rejected() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let mut envelope = TaskLaunchRequestEnvelope::signed(request(), "token", &key);
+        envelope.body.arguments = vec!["ab".to_string()];
+        assert!(!envelope.verify("token", &key.verifying_key()));
+
+        let mut envelope = TaskLaunchRequestEnvelope::signed(request(), "token", &key);
+        envelope.body.file.as_mut().unwrap().hash = vec![3, 2, 1];
+        assert!(!envelope.verify("token", &key.verifying_key()));
+
+        let mut envelope = TaskLaunchRequestEnvelope::signed(request(), "token", &key);
+        envelope.signature = None;
+        assert!(!envelope.verify("token", &key.verifying_key()));
+    }
+}
@@ -1,17 +1,24 @@
 use crate::api::UserAgentHeader;
+use crate::api::protocol::ProtocolRange;
 use axum::http::HeaderValue;
 
 impl Default for UserAgentHeader {
     fn default() -> Self {
         Self {
             version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: ProtocolRange::supported(),
         }
     }
 }
 
+/// Formatted as `Orosu/<version> (protocol <range>)`. Servers before protocol
+/// versioning read everything after the slash as the version, so they still
+/// accept it.
 impl From<UserAgentHeader> for HeaderValue {
     fn from(value: UserAgentHeader) -> Self {
-        format!("Orosu/{}", value.version).parse().unwrap()
+        format!("Orosu/{} (protocol {})", value.version, value.protocol)
+            .parse()
+            .unwrap()
     }
 }
 
@@ -20,15 +27,54 @@ impl TryFrom<&HeaderValue> for UserAgentHeader {
 
     fn try_from(value: &HeaderValue) -> Result<Self, Self::Error> {
         let string = value.to_str()?;
-        let parts: Vec<&str> = string.split('/').collect();
-        if parts.len() != 2 {
+        let Some(product) = string.strip_prefix("Orosu/") else {
             anyhow::bail!("Invalid user agent header: {string}");
-        }
-        if parts[0] != "Orosu" {
+        };
+        let (version, comment) = match product.split_once(' ') {
+            Some((version, comment)) => (version, Some(comment.trim())),
+            None => (product, None),
+        };
+        if version.is_empty() || version.contains('/') {
             anyhow::bail!("Invalid user agent header: {string}");
         }
+        let protocol = match comment {
+            None => ProtocolRange::unversioned(),
+            Some(comment) => comment
+                .strip_prefix("(protocol ")
+                .and_then(|e| e.strip_suffix(')'))
+                .ok_or_else(|| anyhow::anyhow!("Invalid user agent header: {string}"))?
+                .parse()?,
+        };
         Ok(Self {
-            version: parts[1].to_string(),
+            version: version.to_string(),
+            protocol,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::api::UserAgentHeader;
+    use crate::api::protocol::ProtocolRange;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn reads_versioned_and_legacy_user_agents() {
+        let header = HeaderValue::from(UserAgentHeader {
+            version: String::from("0.6.0"),
+            protocol: "1-2".parse().unwrap(),
+        });
+        assert_eq!(header, "Orosu/0.6.0 (protocol 1-2)");
+        let parsed = UserAgentHeader::try_from(&header).unwrap();
+        assert_eq!(parsed.version, "0.6.0");
+        assert_eq!(parsed.protocol, "1-2".parse().unwrap());
+
+        let legacy = UserAgentHeader::try_from(&HeaderValue::from_static("Orosu/0.5.1")).unwrap();
+        assert_eq!(legacy.version, "0.5.1");
+        assert_eq!(legacy.protocol, ProtocolRange::unversioned());
+
+        for invalid in ["curl/8.0", "Orosu/0.6.0 (windows)", "Orosu/a/b"] {
+            assert!(UserAgentHeader::try_from(&HeaderValue::from_static(invalid)).is_err());
+        }
+    }
+}
@@ -1,18 +1,297 @@
 use crate::script::Script;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
 use cidr::IpCidr;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Client {
     #[serde(rename = "name")]
     pub(crate) name: String,
-    #[serde(rename = "secret_file")]
-    pub(crate) secret_file: PathBuf,
-    #[serde(rename = "whitelisted_ips")]
+    #[serde(rename = "secret_file", skip_serializing_if = "Option::is_none")]
+    pub(crate) secret_file: Option<PathBuf>,
+    #[serde(rename = "public_key", skip_serializing_if = "Option::is_none")]
+    pub(crate) public_key: Option<String>,
+    #[serde(rename = "keys", skip_serializing_if = "Option::is_none")]
+    pub(crate) keys: Option<Vec<ClientPublicKey>>,
+    #[serde(rename = "whitelisted_ips", skip_serializing_if = "Option::is_none")]
     pub(crate) whitelisted_ips: Option<Vec<IpCidr>>,
-    #[serde(rename = "blacklisted_ips")]
+    #[serde(rename = "blacklisted_ips", skip_serializing_if = "Option::is_none")]
     pub(crate) blacklisted_ips: Option<Vec<IpCidr>>,
+    #[serde(
+        rename = "whitelisted_ip_groups",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) whitelisted_ip_groups: Option<Vec<String>>,
+    #[serde(
+        rename = "blacklisted_ip_groups",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) blacklisted_ip_groups: Option<Vec<String>>,
+    #[serde(rename = "allowed_uids", skip_serializing_if = "Option::is_none")]
+    pub(crate) allowed_uids: Option<Vec<u32>>,
+    #[serde(rename = "allowed_gids", skip_serializing_if = "Option::is_none")]
+    pub(crate) allowed_gids: Option<Vec<u32>>,
+    #[serde(rename = "allowed_audiences", skip_serializing_if = "Option::is_none")]
+    pub(crate) allowed_audiences: Option<Vec<String>>,
+    #[serde(rename = "oidc", skip_serializing_if = "Option::is_none")]
+    pub(crate) oidc: Option<Vec<OidcRule>>,
+    #[serde(rename = "certificate_names", skip_serializing_if = "Option::is_none")]
+    pub(crate) certificate_names: Option<Vec<String>>,
+    #[serde(
+        rename = "max_attachment_size",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) max_attachment_size: Option<u64>,
+    #[serde(
+        rename = "max_uncompressed_size",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) max_uncompressed_size: Option<u64>,
+    #[serde(rename = "max_entries", skip_serializing_if = "Option::is_none")]
+    pub(crate) max_entries: Option<usize>,
     #[serde(rename = "scripts")]
     pub(crate) scripts: Vec<Script>,
 }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClientPublicKey {
+    #[serde(rename = "label", skip_serializing_if = "Option::is_none")]
+    pub(crate) label: Option<String>,
+    #[serde(rename = "secret_file", skip_serializing_if = "Option::is_none")]
+    pub(crate) secret_file: Option<PathBuf>,
+    #[serde(rename = "public_key", skip_serializing_if = "Option::is_none")]
+    pub(crate) public_key: Option<String>,
+    #[serde(rename = "not_before", skip_serializing_if = "Option::is_none")]
+    pub(crate) not_before: Option<DateTime<Utc>>,
+    #[serde(rename = "not_after", skip_serializing_if = "Option::is_none")]
+    pub(crate) not_after: Option<DateTime<Utc>>,
+}
+
+/// Workflow identity mapped to a client. Every field is a glob pattern, e.g.
+/// `refs/heads/*`, and fields that are not set match any value.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OidcRule {
+    #[serde(rename = "repository")]
+    pub(crate) repository: String,
+    #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub(crate) git_ref: Option<String>,
+    #[serde(rename = "environment", skip_serializing_if = "Option::is_none")]
+    pub(crate) environment: Option<String>,
+}
+
+/// Where the encoded public key of a client comes from.
+pub(crate) enum KeySource<'a> {
+    File(&'a Path),
+    Inline(&'a str),
+}
+
+impl KeySource<'_> {
+    pub(crate) fn read(&self) -> anyhow::Result<String> {
+        match self {
+            KeySource::File(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read public key file {}", path.display())),
+            KeySource::Inline(key) => Ok(key.to_string()),
+        }
+    }
+}
+
+impl Display for KeySource<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeySource::File(path) => write!(f, "{}", path.display()),
+            KeySource::Inline(_) => write!(f, "inline public key"),
+        }
+    }
+}
+
+impl Client {
+    pub fn new(name: String, secret_file: PathBuf) -> Self {
+        Self {
+            name,
+            secret_file: Some(secret_file),
+            public_key: None,
+            keys: None,
+            whitelisted_ips: None,
+            blacklisted_ips: None,
+            whitelisted_ip_groups: None,
+            blacklisted_ip_groups: None,
+            allowed_uids: None,
+            allowed_gids: None,
+            allowed_audiences: None,
+            oidc: None,
+            certificate_names: None,
+            max_attachment_size: None,
+            max_uncompressed_size: None,
+            max_entries: None,
+            scripts: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The client as an item of the `clients` list of the configuration.
+    /// Number of keys configured for the client.
+    pub fn key_count(&self) -> usize {
+        self.public_keys().len()
+    }
+
+    pub fn to_yaml(&self) -> anyhow::Result<String> {
+        serde_saphyr::to_string(&[self]).context("Failed to serialize client configuration")
+    }
+
+    pub fn set_whitelisted_ips(&mut self, whitelisted_ips: Vec<IpCidr>) {
+        self.whitelisted_ips = Some(whitelisted_ips);
+    }
+
+    pub fn set_blacklisted_ips(&mut self, blacklisted_ips: Vec<IpCidr>) {
+        self.blacklisted_ips = Some(blacklisted_ips);
+    }
+
+    /// Adds the script, replacing an existing script with the same name.
+    pub fn add_script(&mut self, script: Script) {
+        self.scripts.retain(|e| e.name != script.name);
+        self.scripts.push(script);
+    }
+
+    /// Takes over the keys and any IP lists and scripts of `other`, keeping
+    /// everything else of this client. All keys of this client are replaced,
+    /// so that a re-registered client cannot authenticate with old keys.
+    pub(crate) fn update(&mut self, other: Client) {
+        self.secret_file = other.secret_file;
+        self.public_key = other.public_key;
+        self.keys = other.keys;
+        if other.whitelisted_ips.is_some() {
+            self.whitelisted_ips = other.whitelisted_ips;
+        }
+        if other.blacklisted_ips.is_some() {
+            self.blacklisted_ips = other.blacklisted_ips;
+        }
+        for script in other.scripts {
+            self.add_script(script);
+        }
+    }
+
+    /// Whether a client certificate with the given subject and alternative
+    /// names belongs to the client: one of them must be listed in
+    /// `certificate_names`, or be the client name if none are configured.
+    pub(crate) fn accepts_certificate(&self, names: &[String]) -> bool {
+        match &self.certificate_names {
+            Some(accepted) => names.iter().any(|name| accepted.contains(name)),
+            None => names.contains(&self.name),
+        }
+    }
+
+    /// Whether the client restricts the Unix socket peers it accepts.
+    pub(crate) fn restricts_peer_credentials(&self) -> bool {
+        self.allowed_uids.is_some() || self.allowed_gids.is_some()
+    }
+
+    /// Whether a Unix socket peer running as `uid` with primary group `gid`
+    /// is listed in `allowed_uids` or `allowed_gids`.
+    pub(crate) fn accepts_peer_credentials(&self, uid: u32, gid: u32) -> bool {
+        self.allowed_uids.iter().flatten().any(|e| *e == uid)
+            || self.allowed_gids.iter().flatten().any(|e| *e == gid)
+    }
+
+    /// All keys configured for the client, including the top-level
+    /// `secret_file` or `public_key`.
+    pub(crate) fn public_keys(&self) -> Vec<ClientPublicKey> {
+        let top_level =
+            (self.secret_file.is_some() || self.public_key.is_some()).then(|| ClientPublicKey {
+                label: None,
+                secret_file: self.secret_file.clone(),
+                public_key: self.public_key.clone(),
+                not_before: None,
+                not_after: None,
+            });
+        top_level
+            .into_iter()
+            .chain(self.keys.iter().flatten().cloned())
+            .collect()
+    }
+
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        let keys = self.public_keys();
+        if keys.is_empty() && self.oidc.is_none() {
+            anyhow::bail!(
+                "Client {} must have either secret_file, public_key, keys or oidc configured",
+                self.name
+            );
+        }
+        for key in &keys {
+            key.source()
+                .with_context(|| format!("Invalid key configuration of client {}", self.name))?;
+        }
+        for rule in self.oidc.iter().flatten() {
+            rule.patterns()
+                .with_context(|| format!("Invalid oidc rule of client {}", self.name))?;
+        }
+        Ok(())
+    }
+}
+
+impl ClientPublicKey {
+    pub(crate) fn source(&self) -> anyhow::Result<KeySource<'_>> {
+        match (&self.secret_file, &self.public_key) {
+            (Some(path), None) => Ok(KeySource::File(path)),
+            (None, Some(key)) => Ok(KeySource::Inline(key)),
+            (Some(_), Some(_)) => {
+                anyhow::bail!("secret_file and public_key cannot be used together")
+            }
+            (None, None) => anyhow::bail!("either secret_file or public_key must be set"),
+        }
+    }
+
+    pub(crate) fn label(&self) -> String {
+        match (&self.label, self.source()) {
+            (Some(label), _) => label.clone(),
+            (None, Ok(source)) => source.to_string(),
+            (None, Err(_)) => String::from("invalid key"),
+        }
+    }
+
+    pub(crate) fn is_valid_at(&self, now: DateTime<Utc>) -> bool {
+        self.not_before.is_none_or(|not_before| not_before <= now)
+            && self.not_after.is_none_or(|not_after| now < not_after)
+    }
+}
+
+impl OidcRule {
+    fn patterns(&self) -> anyhow::Result<[Option<glob::Pattern>; 3]> {
+        let pattern = |value: Option<&String>| {
+            value
+                .map(|e| glob::Pattern::new(e).with_context(|| format!("invalid pattern {e}")))
+                .transpose()
+        };
+        Ok([
+            pattern(Some(&self.repository))?,
+            pattern(self.git_ref.as_ref())?,
+            pattern(self.environment.as_ref())?,
+        ])
+    }
+
+    /// Checks the claims of a token against the rule. The environment only
+    /// matches tokens issued for a job that runs in an environment.
+    pub(crate) fn matches(
+        &self,
+        repository: &str,
+        git_ref: &str,
+        environment: Option<&str>,
+    ) -> bool {
+        let Ok([repository_pattern, ref_pattern, environment_pattern]) = self.patterns() else {
+            return false;
+        };
+        let matches = |pattern: Option<glob::Pattern>, value: Option<&str>| match pattern {
+            None => true,
+            Some(pattern) => value.is_some_and(|value| pattern.matches(value)),
+        };
+        matches(repository_pattern, Some(repository))
+            && matches(ref_pattern, Some(git_ref))
+            && matches(environment_pattern, environment)
+    }
+}
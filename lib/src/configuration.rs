@@ -2,6 +2,7 @@ use crate::client::Client;
 use anyhow::Context;
 use cidr::IpCidr;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
@@ -9,9 +10,143 @@ use std::path::PathBuf;
 pub enum ListenConfiguration {
     #[serde(rename = "tcp")]
     Tcp(SocketAddr),
+    #[serde(rename = "tls")]
+    Tls(TlsListenConfiguration),
     #[cfg(unix)]
     #[serde(rename = "socket")]
-    Socket(PathBuf),
+    Socket(SocketListenConfiguration),
+}
+
+/// Unix socket listener, either just the socket path or the path with the
+/// permissions the socket file is created with.
+#[cfg(unix)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SocketListenConfiguration {
+    Path(PathBuf),
+    Options(SocketOptions),
+}
+
+#[cfg(unix)]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SocketOptions {
+    #[serde(rename = "path")]
+    pub(crate) path: PathBuf,
+    /// Octal file mode, e.g. `"0660"`.
+    #[serde(rename = "mode", skip_serializing_if = "Option::is_none")]
+    pub(crate) mode: Option<String>,
+    #[serde(rename = "owner", skip_serializing_if = "Option::is_none")]
+    pub(crate) owner: Option<String>,
+    #[serde(rename = "group", skip_serializing_if = "Option::is_none")]
+    pub(crate) group: Option<String>,
+}
+
+#[cfg(unix)]
+impl SocketListenConfiguration {
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            SocketListenConfiguration::Path(path) => path,
+            SocketListenConfiguration::Options(options) => &options.path,
+        }
+    }
+
+    pub(crate) fn options(&self) -> Option<&SocketOptions> {
+        match self {
+            SocketListenConfiguration::Path(_) => None,
+            SocketListenConfiguration::Options(options) => Some(options),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl SocketOptions {
+    pub(crate) fn mode(&self) -> anyhow::Result<Option<u32>> {
+        self.mode
+            .as_deref()
+            .map(|mode| {
+                u32::from_str_radix(mode, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .with_context(|| format!("Invalid socket mode {mode}, expected e.g. \"0660\""))
+            })
+            .transpose()
+    }
+}
+
+/// TCP listener serving `wss://`. The certificate and key are PEM files, e.g.
+/// `fullchain.pem` and `privkey.pem` of certbot, and are reloaded when renewed.
+/// With `client_ca`, clients must also present a certificate issued by it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TlsListenConfiguration {
+    #[serde(rename = "address")]
+    pub(crate) address: SocketAddr,
+    #[serde(rename = "certificate")]
+    pub(crate) certificate: PathBuf,
+    #[serde(rename = "private_key")]
+    pub(crate) private_key: PathBuf,
+    #[serde(rename = "client_ca", skip_serializing_if = "Option::is_none")]
+    pub(crate) client_ca: Option<PathBuf>,
+}
+
+/// Limits of connection attempts and the temporary bans of addresses that
+/// repeatedly fail to authenticate. Attempts and failures are counted in
+/// fixed windows of `window` seconds.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RateLimitConfiguration {
+    #[serde(rename = "window", default = "RateLimitConfiguration::default_window")]
+    pub(crate) window: u64,
+    #[serde(
+        rename = "max_attempts_per_ip",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) max_attempts_per_ip: Option<u32>,
+    #[serde(
+        rename = "max_attempts_per_client",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) max_attempts_per_client: Option<u32>,
+    #[serde(
+        rename = "max_failures",
+        default = "RateLimitConfiguration::default_max_failures"
+    )]
+    pub(crate) max_failures: u32,
+    #[serde(
+        rename = "ban_duration",
+        default = "RateLimitConfiguration::default_ban_duration"
+    )]
+    pub(crate) ban_duration: u64,
+    #[serde(rename = "state_file", skip_serializing_if = "Option::is_none")]
+    pub(crate) state_file: Option<PathBuf>,
+    #[serde(rename = "metrics_address", skip_serializing_if = "Option::is_none")]
+    pub(crate) metrics_address: Option<SocketAddr>,
+}
+
+impl RateLimitConfiguration {
+    fn default_window() -> u64 {
+        60
+    }
+
+    fn default_max_failures() -> u32 {
+        5
+    }
+
+    fn default_ban_duration() -> u64 {
+        900
+    }
+}
+
+/// Header that trusted proxies put the client address in.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Default, Clone, Copy)]
+pub enum ClientIpHeader {
+    #[serde(rename = "x_forwarded_for")]
+    #[default]
+    XForwardedFor,
+    #[serde(rename = "x_real_ip")]
+    XRealIp,
+    #[serde(rename = "forwarded")]
+    Forwarded,
+    #[serde(rename = "cf_connecting_ip")]
+    CfConnectingIp,
 }
 
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Default, Clone, clap::ValueEnum)]
@@ -44,27 +179,157 @@ pub struct Configuration {
     pub listen: ListenConfiguration,
     #[serde(rename = "log_level", default)]
     pub log_level: LogLevelConfiguration,
-    #[serde(rename = "whitelisted_ips")]
+    #[serde(rename = "server_id", skip_serializing_if = "Option::is_none")]
+    pub server_id: Option<String>,
+    #[serde(rename = "clock_skew", skip_serializing_if = "Option::is_none")]
+    pub clock_skew: Option<u64>,
+    #[serde(rename = "max_token_lifetime", skip_serializing_if = "Option::is_none")]
+    pub max_token_lifetime: Option<u64>,
+    #[serde(rename = "revocation_file", skip_serializing_if = "Option::is_none")]
+    pub revocation_file: Option<PathBuf>,
+    #[serde(rename = "oidc", skip_serializing_if = "Option::is_none")]
+    pub oidc: Option<OidcConfiguration>,
+    #[serde(rename = "trusted_proxies", skip_serializing_if = "Option::is_none")]
+    pub trusted_proxies: Option<Vec<IpCidr>>,
+    #[serde(rename = "client_ip_header", default)]
+    pub client_ip_header: ClientIpHeader,
+    #[serde(rename = "rate_limit", skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimitConfiguration>,
+    #[serde(rename = "ip_groups", skip_serializing_if = "Option::is_none")]
+    pub ip_groups: Option<BTreeMap<String, Vec<IpCidr>>>,
+    #[serde(rename = "whitelisted_ips", skip_serializing_if = "Option::is_none")]
     pub ip_whitelist: Option<Vec<IpCidr>>,
-    #[serde(rename = "blacklisted_ips")]
+    #[serde(rename = "blacklisted_ips", skip_serializing_if = "Option::is_none")]
     pub ip_blacklist: Option<Vec<IpCidr>>,
     #[serde(rename = "clients")]
     pub clients: Vec<Client>,
 }
 
+/// Identity provider whose tokens are accepted in place of client keys, e.g.
+/// GitHub Actions with issuer `https://token.actions.githubusercontent.com`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OidcConfiguration {
+    #[serde(rename = "issuer")]
+    pub(crate) issuer: String,
+    #[serde(rename = "audience")]
+    pub(crate) audience: String,
+    #[serde(rename = "jwks_file", skip_serializing_if = "Option::is_none")]
+    pub(crate) jwks_file: Option<PathBuf>,
+    #[serde(rename = "jwks_url", skip_serializing_if = "Option::is_none")]
+    pub(crate) jwks_url: Option<String>,
+    /// Accept tokens issued for the plain audience, whose task requests are
+    /// not signed and could be altered by a proxy relaying them.
+    #[serde(rename = "allow_unbound_tokens", default)]
+    pub(crate) allow_unbound_tokens: bool,
+}
+
 impl Configuration {
     pub fn from_file(path: &PathBuf) -> anyhow::Result<Self> {
         let file = std::fs::File::open(path)
             .with_context(|| format!("Failed to open configuration file at {}", path.display()))?;
         let reader = std::io::BufReader::new(file);
-        serde_saphyr::from_reader(reader).with_context(|| "Failed to parse configuration")
+        let configuration: Self =
+            serde_saphyr::from_reader(reader).with_context(|| "Failed to parse configuration")?;
+        configuration.validate()?;
+        Ok(configuration)
+    }
+
+    pub fn to_yaml(&self) -> anyhow::Result<String> {
+        serde_saphyr::to_string(self).with_context(|| "Failed to serialize configuration")
+    }
+
+    /// Adds the client or updates the existing client with the same name.
+    pub fn upsert_client(&mut self, client: Client) {
+        match self.clients.iter_mut().find(|e| e.name == client.name) {
+            Some(existing) => existing.update(client),
+            None => self.clients.push(client),
+        }
+    }
+
+    fn ip_group(&self, name: &str) -> Option<&Vec<IpCidr>> {
+        self.ip_groups.as_ref()?.get(name)
+    }
+
+    /// Adds the CIDRs of the referenced IP groups to the IP lists of clients
+    /// and scripts, so that only the lists need to be checked afterwards.
+    pub(crate) fn expand_ip_groups(&mut self) {
+        let Some(ip_groups) = &self.ip_groups else {
+            return;
+        };
+        let expand = |ips: &mut Option<Vec<IpCidr>>, groups: &Option<Vec<String>>| {
+            for group in groups.iter().flatten() {
+                let cidrs = ip_groups.get(group).into_iter().flatten();
+                ips.get_or_insert_with(Vec::new).extend(cidrs);
+            }
+        };
+        for client in &mut self.clients {
+            expand(&mut client.whitelisted_ips, &client.whitelisted_ip_groups);
+            expand(&mut client.blacklisted_ips, &client.blacklisted_ip_groups);
+            for script in &mut client.scripts {
+                expand(&mut script.whitelisted_ips, &script.whitelisted_ip_groups);
+                expand(&mut script.blacklisted_ips, &script.blacklisted_ip_groups);
+            }
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(rate_limit) = &self.rate_limit
+            && (rate_limit.window == 0 || rate_limit.max_failures == 0)
+        {
+            anyhow::bail!("rate_limit window and max_failures must be positive");
+        }
+        #[cfg(unix)]
+        if let ListenConfiguration::Socket(socket) = &self.listen
+            && let Some(options) = socket.options()
+        {
+            options.mode()?;
+        }
+        if let Some(oidc) = &self.oidc
+            && oidc.jwks_file.is_some() == oidc.jwks_url.is_some()
+        {
+            anyhow::bail!("oidc requires either jwks_file or jwks_url");
+        }
+        for client in &self.clients {
+            client.validate()?;
+            let scripts = client.scripts.iter().map(|script| {
+                (
+                    format!("Script {} of client {}", script.name, client.name),
+                    &script.whitelisted_ip_groups,
+                    &script.blacklisted_ip_groups,
+                )
+            });
+            let lists = std::iter::once((
+                format!("Client {}", client.name),
+                &client.whitelisted_ip_groups,
+                &client.blacklisted_ip_groups,
+            ))
+            .chain(scripts);
+            for (owner, whitelisted, blacklisted) in lists {
+                for group in whitelisted.iter().chain(blacklisted).flatten() {
+                    if self.ip_group(group).is_none() {
+                        anyhow::bail!("{owner} references unknown IP group {group}");
+                    }
+                }
+            }
+            if client.oidc.is_some() && self.oidc.is_none() {
+                anyhow::bail!(
+                    "Client {} has oidc rules but oidc is not configured",
+                    client.name
+                );
+            }
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::configuration::ListenConfiguration::{Socket, Tcp};
-    use crate::configuration::{Configuration, ListenConfiguration, LogLevelConfiguration};
+    use crate::client::Client;
+    use crate::configuration::ListenConfiguration::{Socket, Tcp, Tls};
+    use crate::configuration::{
+        ClientIpHeader, Configuration, ListenConfiguration, LogLevelConfiguration,
+    };
+    use crate::script::Script;
     use cidr::IpCidr;
     use std::net::IpAddr;
     use std::path::PathBuf;
@@ -109,10 +374,344 @@ mod tests {
     fn listen_configuration_socket_deserialization() {
         let yaml = r#"socket: "/tmp/socket""#;
         let configuration: ListenConfiguration = serde_saphyr::from_str(yaml).unwrap();
-        let Socket(path) = configuration else {
+        let Socket(socket) = configuration else {
             panic!("Expected Socket configuration");
         };
-        assert_eq!(path, PathBuf::from("/tmp/socket"));
+        assert_eq!(socket.path(), &PathBuf::from("/tmp/socket"));
+    }
+
+    #[test]
+    fn listen_configuration_socket_options_deserialization() {
+        let yaml = r#"
+socket:
+  path: "/run/orosu/orosu.sock"
+  mode: "0660"
+  owner: "orosu"
+  group: "www-data"
+"#;
+        let configuration: ListenConfiguration = serde_saphyr::from_str(yaml).unwrap();
+        let Socket(socket) = configuration else {
+            panic!("Expected Socket configuration");
+        };
+        assert_eq!(socket.path(), &PathBuf::from("/run/orosu/orosu.sock"));
+        let options = socket.options().unwrap();
+        assert_eq!(options.mode().unwrap(), Some(0o660));
+        assert_eq!(options.owner.as_deref(), Some("orosu"));
+        assert_eq!(options.group.as_deref(), Some("www-data"));
+    }
+
+    #[test]
+    fn listen_configuration_tls_deserialization() {
+        let yaml = r#"
+tls:
+  address: "0.0.0.0:443"
+  certificate: "/etc/letsencrypt/live/example.com/fullchain.pem"
+  private_key: "/etc/letsencrypt/live/example.com/privkey.pem"
+  client_ca: "/etc/orosu/client-ca.pem"
+"#;
+        let configuration: ListenConfiguration = serde_saphyr::from_str(yaml).unwrap();
+        let Tls(tls) = configuration else {
+            panic!("Expected Tls configuration");
+        };
+        assert_eq!(tls.address.port(), 443);
+        assert_eq!(
+            tls.private_key,
+            PathBuf::from("/etc/letsencrypt/live/example.com/privkey.pem")
+        );
+        assert_eq!(
+            tls.client_ca,
+            Some(PathBuf::from("/etc/orosu/client-ca.pem"))
+        );
+    }
+
+    #[test]
+    fn read_trusted_proxies_config() {
+        let contents = r#"
+listen:
+  tcp: "0.0.0.0:8081"
+trusted_proxies:
+  - "10.0.0.0/8"
+client_ip_header: "cf_connecting_ip"
+clients: []
+"#;
+        let configuration: Configuration = serde_saphyr::from_str(contents).unwrap();
+        assert_eq!(
+            configuration.trusted_proxies,
+            Some(vec!["10.0.0.0/8".parse::<IpCidr>().unwrap()])
+        );
+        assert_eq!(
+            configuration.client_ip_header,
+            ClientIpHeader::CfConnectingIp
+        );
+    }
+
+    #[test]
+    fn expand_ip_groups_config() {
+        let contents = r#"
+listen:
+  tcp: "0.0.0.0:8081"
+ip_groups:
+  bastion:
+    - "10.1.0.0/16"
+  office:
+    - "192.0.2.0/24"
+clients:
+  - name: "my-client"
+    secret_file: "my-client.pub"
+    whitelisted_ips:
+      - "198.51.100.1"
+    whitelisted_ip_groups: ["office"]
+    scripts:
+      - name: "db-migrate"
+        command: ["migrate"]
+        whitelisted_ip_groups: ["bastion"]
+      - name: "deploy"
+        command: ["deploy"]
+"#;
+        let mut configuration: Configuration = serde_saphyr::from_str(contents).unwrap();
+        configuration.validate().unwrap();
+        configuration.expand_ip_groups();
+        let client = &configuration.clients[0];
+        assert_eq!(
+            client.whitelisted_ips,
+            Some(vec![
+                "198.51.100.1".parse::<IpCidr>().unwrap(),
+                "192.0.2.0/24".parse::<IpCidr>().unwrap()
+            ])
+        );
+        assert_eq!(
+            client.scripts[0].whitelisted_ips,
+            Some(vec!["10.1.0.0/16".parse::<IpCidr>().unwrap()])
+        );
+        assert_eq!(client.scripts[1].whitelisted_ips, None);
+
+        let unknown_group = contents.replace(r#"["bastion"]"#, r#"["bastoin"]"#);
+        let configuration: Configuration = serde_saphyr::from_str(&unknown_group).unwrap();
+        assert!(configuration.validate().is_err());
+    }
+
+    #[test]
+    fn read_audience_config() {
+        let contents = r#"
+listen:
+  tcp: "0.0.0.0:8081"
+server_id: "production.example.com"
+clients:
+  - name: "my-client"
+    secret_file: "public.key"
+    allowed_audiences:
+      - "production.example.com"
+      - "deploy.example.com"
+    scripts: []
+"#;
+        let configuration: Configuration = serde_saphyr::from_str(contents).unwrap();
+        assert_eq!(
+            configuration.server_id,
+            Some("production.example.com".to_string())
+        );
+        assert_eq!(
+            configuration.clients[0].allowed_audiences,
+            Some(vec![
+                "production.example.com".to_string(),
+                "deploy.example.com".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn read_client_keys_config() {
+        let contents = r#"
+listen:
+  tcp: "0.0.0.0:8081"
+clients:
+  - name: "my-client"
+    keys:
+      - label: "old"
+        secret_file: "old.pub"
+        not_after: "2025-02-01T00:00:00Z"
+      - label: "new"
+        secret_file: "new.pub"
+        not_before: "2025-01-25T00:00:00Z"
+    scripts: []
+"#;
+        let configuration: Configuration = serde_saphyr::from_str(contents).unwrap();
+        let client = &configuration.clients[0];
+        assert_eq!(client.secret_file, None);
+        let keys = client.public_keys();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].label(), "old");
+        assert_eq!(keys[1].secret_file, Some(PathBuf::from("new.pub")));
+
+        let before_rotation = "2025-01-20T00:00:00Z".parse().unwrap();
+        let during_rotation = "2025-01-28T00:00:00Z".parse().unwrap();
+        let after_rotation = "2025-02-01T00:00:00Z".parse().unwrap();
+        assert!(keys[0].is_valid_at(before_rotation));
+        assert!(!keys[1].is_valid_at(before_rotation));
+        assert!(keys[0].is_valid_at(during_rotation));
+        assert!(keys[1].is_valid_at(during_rotation));
+        assert!(!keys[0].is_valid_at(after_rotation));
+        assert!(keys[1].is_valid_at(after_rotation));
+    }
+
+    #[test]
+    fn read_inline_public_key_config() {
+        let contents = r#"
+listen:
+  tcp: "0.0.0.0:8081"
+clients:
+  - name: "my-client"
+    public_key: "O2onvM62pC1io6jQKm8Nc2UyFXcd4kOmOsBIoYtZ2ik="
+    scripts: []
+"#;
+        let configuration: Configuration = serde_saphyr::from_str(contents).unwrap();
+        configuration.validate().unwrap();
+        assert_eq!(
+            configuration.clients[0].public_key,
+            Some("O2onvM62pC1io6jQKm8Nc2UyFXcd4kOmOsBIoYtZ2ik=".to_string())
+        );
+        assert_eq!(configuration.clients[0].public_keys().len(), 1);
+    }
+
+    #[test]
+    fn reject_ambiguous_key_config() {
+        let both = r#"
+listen:
+  tcp: "0.0.0.0:8081"
+clients:
+  - name: "my-client"
+    secret_file: "public.key"
+    public_key: "O2onvM62pC1io6jQKm8Nc2UyFXcd4kOmOsBIoYtZ2ik="
+    scripts: []
+"#;
+        let configuration: Configuration = serde_saphyr::from_str(both).unwrap();
+        assert!(configuration.validate().is_err());
+
+        let neither = r#"
+listen:
+  tcp: "0.0.0.0:8081"
+clients:
+  - name: "my-client"
+    keys:
+      - label: "empty"
+    scripts: []
+"#;
+        let configuration: Configuration = serde_saphyr::from_str(neither).unwrap();
+        assert!(configuration.validate().is_err());
+
+        let missing = r#"
+listen:
+  tcp: "0.0.0.0:8081"
+clients:
+  - name: "my-client"
+    scripts: []
+"#;
+        let configuration: Configuration = serde_saphyr::from_str(missing).unwrap();
+        assert!(configuration.validate().is_err());
+    }
+
+    #[test]
+    fn read_oidc_config() {
+        let contents = r#"
+listen:
+  tcp: "0.0.0.0:8081"
+oidc:
+  issuer: "https://token.actions.githubusercontent.com"
+  audience: "orosu"
+  jwks_url: "https://token.actions.githubusercontent.com/.well-known/jwks"
+clients:
+  - name: "my-client"
+    oidc:
+      - repository: "acme/web"
+        ref: "refs/heads/main"
+        environment: "production"
+    scripts: []
+"#;
+        let configuration: Configuration = serde_saphyr::from_str(contents).unwrap();
+        configuration.validate().unwrap();
+        let rule = &configuration.clients[0].oidc.as_ref().unwrap()[0];
+        assert_eq!(rule.repository, "acme/web");
+        assert_eq!(rule.git_ref.as_deref(), Some("refs/heads/main"));
+        assert_eq!(rule.environment.as_deref(), Some("production"));
+
+        let without_provider = r#"
+listen:
+  tcp: "0.0.0.0:8081"
+clients:
+  - name: "my-client"
+    oidc:
+      - repository: "acme/web"
+    scripts: []
+"#;
+        let configuration: Configuration = serde_saphyr::from_str(without_provider).unwrap();
+        assert!(configuration.validate().is_err());
+    }
+
+    #[test]
+    fn read_certificate_names_config() {
+        let contents = r#"
+listen:
+  tcp: "0.0.0.0:8081"
+clients:
+  - name: "my-client"
+    secret_file: "my-client.pub"
+    scripts: []
+  - name: "other-client"
+    secret_file: "other-client.pub"
+    certificate_names:
+      - "spiffe://example/deploy"
+    scripts: []
+"#;
+        let configuration: Configuration = serde_saphyr::from_str(contents).unwrap();
+        let names = |names: &[&str]| names.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let (client, other) = (&configuration.clients[0], &configuration.clients[1]);
+        assert!(client.accepts_certificate(&names(&["my-client"])));
+        assert!(!client.accepts_certificate(&names(&["other-client"])));
+        assert!(other.accepts_certificate(&names(&["deploy", "spiffe://example/deploy"])));
+        assert!(!other.accepts_certificate(&names(&["other-client"])));
+    }
+
+    #[test]
+    fn upsert_client_adds_and_updates_clients() {
+        let contents = r#"
+listen:
+  tcp: "0.0.0.0:8081"
+clients:
+  - name: "my-client"
+    secret_file: "old.pub"
+    keys:
+      - label: "laptop"
+        secret_file: "laptop.pub"
+    whitelisted_ips:
+      - "127.0.0.1"
+    scripts:
+      - name: "my-script"
+        command:
+          - "echo"
+"#;
+        let mut configuration: Configuration = serde_saphyr::from_str(contents).unwrap();
+
+        let mut updated = Client::new("my-client".to_string(), PathBuf::from("new.pub"));
+        updated.add_script(Script::new(
+            "deploy".to_string(),
+            vec!["bash".to_string(), "deploy.sh".to_string()],
+        ));
+        configuration.upsert_client(updated);
+        configuration.upsert_client(Client::new(
+            "other-client".to_string(),
+            PathBuf::from("other.pub"),
+        ));
+
+        let configuration: Configuration =
+            serde_saphyr::from_str(&configuration.to_yaml().unwrap()).unwrap();
+        configuration.validate().unwrap();
+        assert_eq!(configuration.clients.len(), 2);
+        let client = &configuration.clients[0];
+        assert_eq!(client.secret_file, Some(PathBuf::from("new.pub")));
+        assert!(client.keys.is_none());
+        assert!(client.whitelisted_ips.is_some());
+        assert_eq!(client.scripts.len(), 2);
+        assert_eq!(client.scripts[1].name, "deploy");
+        assert_eq!(configuration.clients[1].name, "other-client");
     }
 
     #[test]
@@ -153,7 +752,7 @@ clients:
         assert_eq!(configuration.clients[0].scripts[0].command[0], "echo");
         assert_eq!(
             configuration.clients[0].secret_file,
-            PathBuf::from("public.key")
+            Some(PathBuf::from("public.key"))
         );
         assert_eq!(
             configuration.clients[0].whitelisted_ips,
@@ -1,10 +1,10 @@
 extern crate alloc;
 
 pub mod api;
-mod client;
+pub mod client;
 pub mod configuration;
 pub mod cryptography;
-mod script;
+pub mod script;
 pub mod server;
 pub mod server_address;
 pub mod tasks;
@@ -1,11 +1,55 @@
+use cidr::IpCidr;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Script {
     #[serde(rename = "name")]
     pub(crate) name: String,
-    #[serde(rename = "run_as")]
+    #[serde(rename = "run_as", skip_serializing_if = "Option::is_none")]
     pub(crate) run_as: Option<String>,
     #[serde(rename = "command")]
     pub(crate) command: Vec<String>,
+    #[serde(rename = "whitelisted_ips", skip_serializing_if = "Option::is_none")]
+    pub(crate) whitelisted_ips: Option<Vec<IpCidr>>,
+    #[serde(rename = "blacklisted_ips", skip_serializing_if = "Option::is_none")]
+    pub(crate) blacklisted_ips: Option<Vec<IpCidr>>,
+    #[serde(
+        rename = "whitelisted_ip_groups",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) whitelisted_ip_groups: Option<Vec<String>>,
+    #[serde(
+        rename = "blacklisted_ip_groups",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) blacklisted_ip_groups: Option<Vec<String>>,
+    #[serde(
+        rename = "max_attachment_size",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) max_attachment_size: Option<u64>,
+    #[serde(
+        rename = "max_uncompressed_size",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) max_uncompressed_size: Option<u64>,
+    #[serde(rename = "max_entries", skip_serializing_if = "Option::is_none")]
+    pub(crate) max_entries: Option<usize>,
+}
+
+impl Script {
+    pub fn new(name: String, command: Vec<String>) -> Self {
+        Self {
+            name,
+            run_as: None,
+            command,
+            whitelisted_ips: None,
+            blacklisted_ips: None,
+            whitelisted_ip_groups: None,
+            blacklisted_ip_groups: None,
+            max_attachment_size: None,
+            max_uncompressed_size: None,
+            max_entries: None,
+        }
+    }
 }
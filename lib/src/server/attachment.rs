@@ -0,0 +1,280 @@
+use crate::client::Client;
+use crate::script::Script;
+use std::fs::File;
+use std::io::{Read, Seek};
+use tempfile::TempDir;
+use zip::ZipArchive;
+
+/// Largest attachment accepted if neither the script nor the client sets
+/// `max_attachment_size`.
+pub const DEFAULT_MAX_ATTACHMENT_SIZE: u64 = 1024 * 1024 * 1024;
+/// Largest total size of the extracted files if neither the script nor the
+/// client sets `max_uncompressed_size`.
+pub const DEFAULT_MAX_UNCOMPRESSED_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+/// Most archive entries accepted if neither the script nor the client sets
+/// `max_entries`.
+pub const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+/// Limits on the attachment of a task, set by the script or else by the
+/// client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttachmentLimits {
+    pub max_attachment_size: u64,
+    pub max_uncompressed_size: u64,
+    pub max_entries: usize,
+}
+
+impl AttachmentLimits {
+    pub fn of(client: &Client, script: &Script) -> Self {
+        Self {
+            max_attachment_size: script
+                .max_attachment_size
+                .or(client.max_attachment_size)
+                .unwrap_or(DEFAULT_MAX_ATTACHMENT_SIZE),
+            max_uncompressed_size: script
+                .max_uncompressed_size
+                .or(client.max_uncompressed_size)
+                .unwrap_or(DEFAULT_MAX_UNCOMPRESSED_SIZE),
+            max_entries: script
+                .max_entries
+                .or(client.max_entries)
+                .unwrap_or(DEFAULT_MAX_ENTRIES),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AttachmentError {
+    /// The archive exceeds one of the limits.
+    TooLarge(String),
+    /// The archive is malformed or has entries that cannot be extracted
+    /// safely.
+    Invalid(String),
+    /// The archive could not be written to disk.
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for AttachmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttachmentError::TooLarge(message) | AttachmentError::Invalid(message) => {
+                write!(f, "{message}")
+            }
+            AttachmentError::Io(e) => write!(f, "cannot extract attachment: {e}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for AttachmentError {
+    fn from(e: std::io::Error) -> Self {
+        AttachmentError::Io(e)
+    }
+}
+
+/// Extracts a zip archive into a new temporary directory. Entries must stay
+/// inside the directory and cannot be symlinks. The entry count and the
+/// number of bytes actually written are checked against `limits`, as the
+/// sizes declared in the archive cannot be trusted.
+pub fn extract(
+    archive: impl Read + Seek,
+    limits: &AttachmentLimits,
+) -> Result<TempDir, AttachmentError> {
+    let mut archive = ZipArchive::new(archive)
+        .map_err(|e| AttachmentError::Invalid(format!("cannot read archive: {e}")))?;
+    if archive.len() > limits.max_entries {
+        return Err(AttachmentError::TooLarge(format!(
+            "archive has {} entries, at most {} are allowed",
+            archive.len(),
+            limits.max_entries
+        )));
+    }
+
+    let directory = TempDir::new()?;
+    tracing::debug!(
+        "Created temporary directory for attached files: {}",
+        directory.path().display()
+    );
+
+    let mut remaining = limits.max_uncompressed_size;
+    for i in 0..archive.len() {
+        let mut entry = archive
+            .by_index(i)
+            .map_err(|e| AttachmentError::Invalid(format!("cannot read entry {i}: {e}")))?;
+        if entry.is_symlink() {
+            return Err(AttachmentError::Invalid(format!(
+                "entry {} is a symlink",
+                entry.name()
+            )));
+        }
+        let Some(name) = entry.enclosed_name() else {
+            return Err(AttachmentError::Invalid(format!(
+                "entry {} points outside of the archive",
+                entry.name()
+            )));
+        };
+        if entry.size() > remaining {
+            return Err(too_large(limits));
+        }
+
+        let output_path = directory.path().join(name);
+        if entry.is_dir() {
+            std::fs::create_dir_all(&output_path)?;
+        } else {
+            if let Some(parent) = output_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut outfile = File::create(&output_path)?;
+            let written = std::io::copy(&mut (&mut entry).take(remaining + 1), &mut outfile)
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::InvalidData => AttachmentError::Invalid(format!(
+                        "cannot decompress entry {}: {e}",
+                        entry.name()
+                    )),
+                    _ => AttachmentError::Io(e),
+                })?;
+            if written > remaining {
+                return Err(too_large(limits));
+            }
+            remaining -= written;
+        }
+        tracing::debug!("Extracted: {}", output_path.display());
+    }
+    tracing::debug!(
+        "Successfully extracted archive to {}",
+        directory.path().display()
+    );
+    Ok(directory)
+}
+
+fn too_large(limits: &AttachmentLimits) -> AttachmentError {
+    AttachmentError::TooLarge(format!(
+        "archive extracts to more than {} bytes",
+        limits.max_uncompressed_size
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::Configuration;
+    use crate::server::attachment::{
+        AttachmentError, AttachmentLimits, DEFAULT_MAX_UNCOMPRESSED_SIZE, extract,
+    };
+    use std::io::{Cursor, Write};
+    use zip::ZipWriter;
+    use zip::write::SimpleFileOptions;
+
+    fn limits() -> AttachmentLimits {
+        AttachmentLimits {
+            max_attachment_size: 1024,
+            max_uncompressed_size: 100,
+            max_entries: 3,
+        }
+    }
+
+    fn archive(build: impl FnOnce(&mut ZipWriter<Cursor<Vec<u8>>>)) -> Cursor<Vec<u8>> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        build(&mut writer);
+        let mut cursor = writer.finish().unwrap();
+        cursor.set_position(0);
+        cursor
+    }
+
+    fn add_file(writer: &mut ZipWriter<Cursor<Vec<u8>>>, name: &str, contents: &[u8]) {
+        writer
+            .start_file(name, SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(contents).unwrap();
+    }
+
+    #[test]
+    fn extracts_files_within_limits() {
+        let archive = archive(|writer| {
+            add_file(writer, "build/app", b"binary");
+            add_file(writer, "build/../README", b"readme");
+        });
+        let directory = extract(archive, &limits()).unwrap();
+        let read = |name: &str| std::fs::read(directory.path().join(name)).unwrap();
+        assert_eq!(read("build/app"), b"binary");
+        assert_eq!(read("README"), b"readme");
+    }
+
+    #[test]
+    fn rejects_unsafe_entries() {
+        let outside = archive(|writer| add_file(writer, "../escape", b"data"));
+        assert!(matches!(
+            extract(outside, &limits()),
+            Err(AttachmentError::Invalid(_))
+        ));
+
+        let absolute = archive(|writer| add_file(writer, "/tmp/escape", b"data"));
+        assert!(matches!(
+            extract(absolute, &limits()),
+            Err(AttachmentError::Invalid(_))
+        ));
+
+        let symlink = archive(|writer| {
+            writer
+                .add_symlink("link", "/etc", SimpleFileOptions::default())
+                .unwrap();
+        });
+        assert!(matches!(
+            extract(symlink, &limits()),
+            Err(AttachmentError::Invalid(_))
+        ));
+
+        let garbage = Cursor::new(b"not an archive".to_vec());
+        assert!(matches!(
+            extract(garbage, &limits()),
+            Err(AttachmentError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let entries = archive(|writer| {
+            for i in 0..4 {
+                add_file(writer, &format!("file-{i}"), b"data");
+            }
+        });
+        assert!(matches!(
+            extract(entries, &limits()),
+            Err(AttachmentError::TooLarge(_))
+        ));
+
+        let size = archive(|writer| {
+            add_file(writer, "first", &[0; 60]);
+            add_file(writer, "second", &[0; 60]);
+        });
+        assert!(matches!(
+            extract(size, &limits()),
+            Err(AttachmentError::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn script_limits_take_precedence() {
+        let contents = r#"
+listen:
+  tcp: "0.0.0.0:8081"
+clients:
+  - name: "my-client"
+    secret_file: "my-client.pub"
+    max_attachment_size: 1048576
+    max_entries: 100
+    scripts:
+      - name: "deploy"
+        command: ["deploy"]
+        max_entries: 10
+      - name: "migrate"
+        command: ["migrate"]
+"#;
+        let configuration: Configuration = serde_saphyr::from_str(contents).unwrap();
+        let client = &configuration.clients[0];
+        let deploy = AttachmentLimits::of(client, &client.scripts[0]);
+        assert_eq!(deploy.max_attachment_size, 1048576);
+        assert_eq!(deploy.max_uncompressed_size, DEFAULT_MAX_UNCOMPRESSED_SIZE);
+        assert_eq!(deploy.max_entries, 10);
+        let migrate = AttachmentLimits::of(client, &client.scripts[1]);
+        assert_eq!(migrate.max_entries, 100);
+    }
+}
@@ -0,0 +1,189 @@
+use crate::api::protocol::ProtocolRange;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::fmt::{Display, Formatter};
+
+/// Reason an authentication attempt was rejected. The message is sent to the
+/// client as the response body, so it must not tell an unknown client apart
+/// from a wrong key; those details only go to the server log.
+#[derive(Debug)]
+pub enum AuthError {
+    MissingUserAgent,
+    InvalidUserAgent,
+    UnsupportedProtocol { client: ProtocolRange },
+    MissingAuthorization,
+    InvalidAuthorization,
+    MalformedToken,
+    MissingClaim(String),
+    InvalidCredentials,
+    CertificateMismatch,
+    InvalidAudience,
+    InvalidIssuer,
+    Expired { server_time: DateTime<Utc> },
+    NotYetValid { server_time: DateTime<Utc> },
+    IssuedInFuture { server_time: DateTime<Utc> },
+    LifetimeTooLong { max_lifetime: u64 },
+    RevokedKey,
+    GrantExpired,
+    ScopesOutsideGrant,
+    Replayed,
+    OidcNotConfigured,
+    UnknownOidcIdentity,
+    UnboundOidcToken,
+    TooManyAttempts,
+    Busy,
+    Internal,
+}
+
+/// Response extension marking a failed authentication attempt, which counts
+/// towards bans.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthFailure;
+
+impl AuthError {
+    /// Whether the rejection counts as a failed authentication attempt. All
+    /// do, except those that are not the client's fault and clock errors.
+    /// Clock errors are raised after the token signature was verified, so
+    /// they cannot be used for probing, and counting them would ban the
+    /// shared address of runners with drifting clocks.
+    fn counts_as_failure(&self) -> bool {
+        !matches!(
+            self,
+            AuthError::Expired { .. }
+                | AuthError::NotYetValid { .. }
+                | AuthError::IssuedInFuture { .. }
+                | AuthError::TooManyAttempts
+                | AuthError::Busy
+                | AuthError::Internal
+        )
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            AuthError::UnsupportedProtocol { .. } => StatusCode::UPGRADE_REQUIRED,
+            AuthError::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+            AuthError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            AuthError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let time = |time: &DateTime<Utc>| time.to_rfc3339_opts(SecondsFormat::Secs, true);
+        match self {
+            AuthError::MissingUserAgent => write!(f, "user agent header is missing"),
+            AuthError::InvalidUserAgent => write!(f, "unexpected user agent header format"),
+            AuthError::UnsupportedProtocol { client } => {
+                let server = ProtocolRange::supported();
+                let upgrade = if client.min > server.max {
+                    "orosu-server"
+                } else {
+                    "orosu-client"
+                };
+                write!(
+                    f,
+                    "client speaks protocol versions {client}, server speaks {server}; upgrade {upgrade}"
+                )
+            }
+            AuthError::MissingAuthorization => write!(f, "authorization header is missing"),
+            AuthError::InvalidAuthorization => write!(f, "invalid authorization header format"),
+            AuthError::MalformedToken => write!(f, "malformed token"),
+            AuthError::MissingClaim(claim) => write!(f, "token has no {claim} claim"),
+            AuthError::InvalidCredentials => write!(f, "invalid credentials"),
+            AuthError::CertificateMismatch => {
+                write!(f, "client certificate does not belong to this client")
+            }
+            AuthError::InvalidAudience => {
+                write!(f, "token audience is not accepted by this server")
+            }
+            AuthError::InvalidIssuer => write!(f, "token issuer is not accepted"),
+            AuthError::Expired { server_time } => write!(
+                f,
+                "token has expired, server time is {}; check the client clock or increase the token lifetime",
+                time(server_time)
+            ),
+            AuthError::NotYetValid { server_time } => write!(
+                f,
+                "token is not valid yet, server time is {}; check the client clock",
+                time(server_time)
+            ),
+            AuthError::IssuedInFuture { server_time } => write!(
+                f,
+                "token was issued in the future, server time is {}; check the client clock",
+                time(server_time)
+            ),
+            AuthError::LifetimeTooLong { max_lifetime } => write!(
+                f,
+                "token lifetime exceeds the {max_lifetime} seconds accepted by the server; lower the token lifetime"
+            ),
+            AuthError::RevokedKey => write!(f, "key has been revoked"),
+            AuthError::GrantExpired => write!(f, "restricted key has expired"),
+            AuthError::ScopesOutsideGrant => {
+                write!(f, "token requests scopes beyond its restricted key")
+            }
+            AuthError::Replayed => write!(f, "token has already been used"),
+            AuthError::OidcNotConfigured => {
+                write!(f, "OIDC authentication is not enabled on this server")
+            }
+            AuthError::UnknownOidcIdentity => {
+                write!(f, "no client is configured for this workflow identity")
+            }
+            AuthError::UnboundOidcToken => write!(
+                f,
+                "OIDC token is not bound to a request key; request it with orosu-client --oidc-audience"
+            ),
+            AuthError::TooManyAttempts => {
+                write!(
+                    f,
+                    "too many connection attempts of this client, retry later"
+                )
+            }
+            AuthError::Busy => write!(f, "server is busy, retry later"),
+            AuthError::Internal => write!(f, "internal server error"),
+        }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), self.to_string()).into_response();
+        if self.counts_as_failure() {
+            response.extensions_mut().insert(AuthFailure);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::auth_error::{AuthError, AuthFailure};
+    use axum::response::IntoResponse;
+    use chrono::Utc;
+
+    #[test]
+    fn marks_failed_authentication_attempts() {
+        let marked = |error: AuthError| {
+            error
+                .into_response()
+                .extensions()
+                .get::<AuthFailure>()
+                .is_some()
+        };
+        assert!(marked(AuthError::InvalidCredentials));
+        assert!(marked(AuthError::Replayed));
+        assert!(marked(AuthError::RevokedKey));
+        assert!(marked(AuthError::MalformedToken));
+        assert!(marked(AuthError::InvalidAuthorization));
+        assert!(marked(AuthError::MissingAuthorization));
+        let server_time = Utc::now();
+        assert!(!marked(AuthError::Expired { server_time }));
+        assert!(!marked(AuthError::NotYetValid { server_time }));
+        assert!(!marked(AuthError::IssuedInFuture { server_time }));
+        assert!(!marked(AuthError::TooManyAttempts));
+        assert!(!marked(AuthError::Busy));
+        assert!(!marked(AuthError::Internal));
+    }
+}
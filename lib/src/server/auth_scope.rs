@@ -1,17 +1,29 @@
 use crate::api::UserAgentHeader;
-use crate::cryptography::Claims;
+use crate::api::protocol::{PROTOCOL_VERSION, ProtocolRange};
+use crate::client::Client;
+use crate::cryptography::{Claims, ScopeGrant, fingerprint};
+use crate::server::auth_error::AuthError;
+use crate::server::authorized_keys::AuthorizedKey;
+use crate::server::nonce_cache::NonceCacheError;
+use crate::server::oidc::find_client;
+use crate::server::peer::PeerInfo;
+use crate::server::token_validation::{
+    check_issued_at, check_lifetime, is_claim_error, token_error, token_validation,
+};
 use crate::server::{AuthContext, AuthScope, ServerState, WorkerAuthContext};
 use axum::Extension;
-use axum::extract::FromRequestParts;
-use axum::http::StatusCode;
+use axum::extract::{ConnectInfo, FromRequestParts};
 use axum::http::header::AUTHORIZATION;
 use axum::http::request::Parts;
 use base64::Engine;
-use base64::engine::general_purpose::STANDARD;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
 use ed25519_dalek::VerifyingKey;
 use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
 use std::sync::Arc;
-use std::time::SystemTime;
 use tokio_tungstenite::tungstenite::http::header::USER_AGENT;
 
 impl AuthScope {
@@ -21,7 +33,7 @@ impl AuthScope {
 }
 
 impl FromRequestParts<Arc<ServerState>> for AuthContext {
-    type Rejection = StatusCode;
+    type Rejection = AuthError;
 
     async fn from_request_parts(
         parts: &mut Parts,
@@ -31,109 +43,475 @@ impl FromRequestParts<Arc<ServerState>> for AuthContext {
             .extensions
             .get::<AuthScope>()
             .cloned()
-            .ok_or(StatusCode::UNAUTHORIZED)?;
+            .ok_or(AuthError::Internal)?;
 
         let Some(user_agent_header) = parts.headers.get(USER_AGENT) else {
             tracing::error!("User agent header is missing");
-            return Err(StatusCode::UNAUTHORIZED);
+            return Err(AuthError::MissingUserAgent);
         };
 
         let user_agent_header: UserAgentHeader = match user_agent_header.try_into() {
             Ok(value) => value,
             Err(e) => {
                 tracing::error!("Unexpected user agent header format: {e}");
-                return Err(StatusCode::UNAUTHORIZED);
+                return Err(AuthError::InvalidUserAgent);
             }
         };
+        let Some(protocol) = ProtocolRange::supported().negotiate(user_agent_header.protocol)
+        else {
+            tracing::error!(
+                "Client version {} speaks unsupported protocol versions {}",
+                user_agent_header.version,
+                user_agent_header.protocol
+            );
+            return Err(AuthError::UnsupportedProtocol {
+                client: user_agent_header.protocol,
+            });
+        };
 
         match scope {
             AuthScope::Worker => {
                 let Some(auth_header) = &parts.headers.get(AUTHORIZATION) else {
                     tracing::error!("Authorization header is missing");
-                    return Err(StatusCode::UNAUTHORIZED);
+                    return Err(AuthError::MissingAuthorization);
                 };
-                let auth_header_value =
-                    auth_header.to_str().map_err(|_| StatusCode::UNAUTHORIZED)?;
-                let parts = auth_header_value
+                let auth_header_value = auth_header
+                    .to_str()
+                    .map_err(|_| AuthError::InvalidAuthorization)?;
+                let (scheme, token) = auth_header_value
                     .split_once(' ')
-                    .ok_or(StatusCode::UNAUTHORIZED)?;
-                if parts.0 != "Token" {
-                    tracing::error!("Invalid authorization header format");
-                    return Err(StatusCode::UNAUTHORIZED);
-                }
-                let token = parts.1;
-
-                let token_data = jsonwebtoken::dangerous::insecure_decode::<Claims>(token)
-                    .map_err(|e| {
-                        tracing::error!("Invalid JWT token: {e}");
-                        StatusCode::UNAUTHORIZED
-                    })?;
-
-                let exp = token_data.claims.exp;
-
-                let now = SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .map_err(|e| {
-                        tracing::error!("Failed to get current time: {e}");
-                        StatusCode::INTERNAL_SERVER_ERROR
-                    })?
-                    .as_secs() as usize;
-
-                if exp < now {
-                    tracing::error!("Token expired");
-                    return Err(StatusCode::UNAUTHORIZED);
-                }
-
-                let client_name = token_data.claims.sub;
-
-                let Some(client) = state.clients.iter().find(|e| e.name == client_name) else {
-                    tracing::error!("Client with provided secret not found");
-                    return Err(StatusCode::UNAUTHORIZED);
+                    .ok_or(AuthError::InvalidAuthorization)?;
+                let peer = parts
+                    .extensions
+                    .get::<ConnectInfo<PeerInfo>>()
+                    .map(|ConnectInfo(peer)| peer);
+                let context = match scheme {
+                    "Token" if protocol < PROTOCOL_VERSION => {
+                        authenticate_legacy_key(token, state, peer, &user_agent_header, protocol)?
+                    }
+                    "Token" => authenticate_key(token, state, peer, &user_agent_header, protocol)?,
+                    "Bearer" => {
+                        authenticate_oidc(token, state, peer, &user_agent_header, protocol).await?
+                    }
+                    _ => {
+                        tracing::error!("Invalid authorization header format");
+                        return Err(AuthError::InvalidAuthorization);
+                    }
                 };
+                if let Some(limiter) = &state.rate_limiter
+                    && limiter
+                        .check_client(&context.client.name, Utc::now())
+                        .is_err()
+                {
+                    return Err(AuthError::TooManyAttempts);
+                }
+                Ok(AuthContext::Worker(context))
+            }
+        }
+    }
+}
 
-                let secret_file = client.secret_file.clone();
-                let public_key = {
-                    let file = secret_file;
-                    let key = std::fs::read_to_string(file).map_err(|e| {
-                        tracing::error!("Failed to read public key file: {e}");
-                        StatusCode::UNAUTHORIZED
-                    })?;
-                    let Ok(bytes) = STANDARD.decode(key.trim()) else {
-                        tracing::error!("Invalid public key format");
-                        return Err(StatusCode::UNAUTHORIZED);
-                    };
-                    let key: VerifyingKey = bytes
-                        .as_slice()
-                        .try_into()
-                        .map_err(|_| StatusCode::UNAUTHORIZED)?;
-                    DecodingKey::from_ed_der(key.as_bytes())
-                };
+/// Requires the client certificate, if the listener asks for one, to belong
+/// to the authenticated client.
+fn check_certificate(peer: Option<&PeerInfo>, client: &Client) -> Result<(), AuthError> {
+    let Some(names) = peer.and_then(|peer| peer.certificate_names.as_ref()) else {
+        return Ok(());
+    };
+    if !client.accepts_certificate(names) {
+        tracing::error!(
+            "Client certificate with names {names:?} does not belong to client {}",
+            client.name
+        );
+        return Err(AuthError::CertificateMismatch);
+    }
+    Ok(())
+}
 
-                if let Err(e) = jsonwebtoken::decode::<Claims>(
-                    token,
-                    &public_key,
-                    &Validation::new(Algorithm::EdDSA),
-                ) {
-                    tracing::error!("Invalid JWT token: {e}");
-                    return Err(StatusCode::UNAUTHORIZED);
-                };
+/// Records the token identifier so that the token cannot be used again.
+fn remember_token(
+    state: &ServerState,
+    token_id: &str,
+    expires_at: usize,
+    now: DateTime<Utc>,
+    client_name: &str,
+) -> Result<(), AuthError> {
+    match state
+        .nonces
+        .insert(token_id, expires_at, now.timestamp() as usize)
+    {
+        Ok(()) => Ok(()),
+        Err(NonceCacheError::Replayed) => {
+            tracing::error!("Token {token_id} of client {client_name} was replayed");
+            Err(AuthError::Replayed)
+        }
+        Err(NonceCacheError::Full) => {
+            tracing::error!("Token nonce cache is full, rejecting client {client_name}");
+            Err(AuthError::Busy)
+        }
+    }
+}
 
-                tracing::info!(
-                    "Client {}, version {} authenticated",
+/// Finds the active key of the client that signed the token and returns it
+/// with the token claims. `signing_key` gives the key that checks the
+/// signature on behalf of a client key, if that key can have signed it.
+fn verify_signature<'a, C: DeserializeOwned>(
+    token: &str,
+    client: &Client,
+    state: &'a ServerState,
+    validation: &Validation,
+    now: DateTime<Utc>,
+    signing_key: impl Fn(&AuthorizedKey) -> Option<VerifyingKey>,
+) -> Result<(&'a AuthorizedKey, C), AuthError> {
+    for public_key in state.keys.get(&client.name).into_iter().flatten() {
+        if !public_key.is_valid_at(now) {
+            tracing::debug!(
+                "Skipping key {} of client {} outside of its validity period",
+                public_key.label(),
+                client.name
+            );
+            continue;
+        }
+        let Some(signing_key) = signing_key(public_key) else {
+            continue;
+        };
+        let decoding_key = DecodingKey::from_ed_der(signing_key.as_bytes());
+        match jsonwebtoken::decode::<C>(token, &decoding_key, validation) {
+            Ok(token_data) => return Ok((public_key, token_data.claims)),
+            Err(e) if is_claim_error(&e) => {
+                tracing::error!(
+                    "Token of client {} signed with key {} was rejected: {e}",
+                    client.name,
+                    public_key.label()
+                );
+                return Err(token_error(&e, now));
+            }
+            Err(e) => {
+                tracing::debug!(
+                    "Token of client {} does not match key {}: {e}",
                     client.name,
-                    user_agent_header.version
+                    public_key.label()
                 );
+            }
+        }
+    }
+    tracing::error!(
+        "Invalid JWT token: no active key of client {} accepted it",
+        client.name
+    );
+    Err(AuthError::InvalidCredentials)
+}
+
+/// Authenticates a token signed with one of the client's keys or with a
+/// restricted key derived from one.
+fn authenticate_key(
+    token: &str,
+    state: &ServerState,
+    peer: Option<&PeerInfo>,
+    user_agent_header: &UserAgentHeader,
+    protocol: u32,
+) -> Result<WorkerAuthContext, AuthError> {
+    let token_data = jsonwebtoken::dangerous::insecure_decode::<Claims>(token).map_err(|e| {
+        tracing::error!("Invalid JWT token: {e}");
+        AuthError::MalformedToken
+    })?;
+
+    let client_name = token_data.claims.sub;
+
+    let Some(client) = state.clients.iter().find(|e| e.name == client_name) else {
+        tracing::error!("Client {client_name} not found");
+        return Err(AuthError::InvalidCredentials);
+    };
+    check_certificate(peer, client)?;
 
-                Ok(AuthContext::Worker(WorkerAuthContext {
-                    client: client.clone(),
-                }))
+    let mut validation = token_validation(Algorithm::EdDSA, state.clock_skew);
+    match client
+        .allowed_audiences
+        .clone()
+        .or_else(|| state.server_id.clone().map(|id| vec![id]))
+    {
+        Some(audiences) => {
+            validation.set_audience(&audiences);
+            validation.set_required_spec_claims(&["exp", "sub", "aud"]);
+        }
+        None => validation.validate_aud = false,
+    }
+
+    let grant = match &token_data.claims.grant {
+        None => None,
+        Some(grant) => match ScopeGrant::decode(grant) {
+            Ok(grant) => Some(grant),
+            Err(e) => {
+                tracing::error!("Invalid scope grant of client {}: {e}", client.name);
+                return Err(AuthError::MalformedToken);
+            }
+        },
+    };
+    let restricted_key = match &grant {
+        None => None,
+        Some(grant) => match grant.verifying_key() {
+            Ok(key) => Some(key),
+            Err(e) => {
+                tracing::error!("Invalid scope grant of client {}: {e}", client.name);
+                return Err(AuthError::MalformedToken);
             }
+        },
+    };
+
+    let now = Utc::now();
+    let (public_key, claims) = verify_signature::<Claims>(
+        token,
+        client,
+        state,
+        &validation,
+        now,
+        |public_key| match &grant {
+            Some(grant) if !grant.verify(&public_key.key) => None,
+            _ => Some(restricted_key.unwrap_or(public_key.key)),
+        },
+    )?;
+
+    if let Err(e) = check_issued_at(claims.iat, now, &validation) {
+        tracing::error!("Token of client {} was rejected: {e}", client.name);
+        return Err(e);
+    }
+    let issued_at = claims.iat.unwrap_or_default();
+    if let Err(e) = check_lifetime(issued_at, claims.exp, state.max_token_lifetime) {
+        tracing::error!("Token of client {} was rejected: {e}", client.name);
+        return Err(e);
+    }
+
+    if state.revocations.is_revoked(&public_key.fingerprint) {
+        tracing::error!(
+            "Client {} used revoked key {} ({})",
+            client.name,
+            public_key.label(),
+            public_key.fingerprint
+        );
+        return Err(AuthError::RevokedKey);
+    }
+
+    if let (Some(grant), Some(restricted_key)) = (&grant, &restricted_key) {
+        let restricted_fingerprint = fingerprint(restricted_key);
+        if state.revocations.is_revoked(&restricted_fingerprint) {
+            tracing::error!(
+                "Client {} used revoked restricted key {restricted_fingerprint}",
+                client.name
+            );
+            return Err(AuthError::RevokedKey);
+        }
+        if grant.client_name != client.name {
+            tracing::error!(
+                "Restricted key {restricted_fingerprint} was granted to {}, not {}",
+                grant.client_name,
+                client.name
+            );
+            return Err(AuthError::InvalidCredentials);
+        }
+        if !grant.is_valid_at(now) {
+            tracing::error!(
+                "Restricted key {restricted_fingerprint} of client {} has expired",
+                client.name
+            );
+            return Err(AuthError::GrantExpired);
+        }
+        let within_grant = claims
+            .scopes
+            .as_ref()
+            .is_some_and(|scopes| scopes.iter().all(|e| grant.scopes.contains(e)));
+        if !within_grant {
+            tracing::error!(
+                "Token of client {} requests scopes beyond its grant",
+                client.name
+            );
+            return Err(AuthError::ScopesOutsideGrant);
         }
     }
+
+    let expires_at = claims.exp + validation.leeway as usize;
+    remember_token(state, &claims.jti, expires_at, now, &client.name)?;
+
+    match &restricted_key {
+        None => tracing::info!(
+            "Client {}, version {} authenticated with key {}",
+            client.name,
+            user_agent_header.version,
+            public_key.label()
+        ),
+        Some(restricted_key) => tracing::info!(
+            "Client {}, version {} authenticated with restricted key {} granted by key {}",
+            client.name,
+            user_agent_header.version,
+            fingerprint(restricted_key),
+            public_key.label()
+        ),
+    }
+
+    Ok(WorkerAuthContext {
+        client: client.clone(),
+        key: Some(restricted_key.unwrap_or(public_key.key)),
+        token_id: claims.jti,
+        scopes: claims.scopes,
+        protocol,
+    })
+}
+
+/// Claims of the tokens of clients released before protocol versioning.
+#[derive(Debug, Deserialize)]
+struct LegacyClaims {
+    sub: String,
+    exp: usize,
+    /// Only set by current clients, whose tokens are not accepted without
+    /// the request signature of protocol version 2.
+    jti: Option<String>,
+}
+
+/// Authenticates a client released before protocol versioning, during the
+/// deprecation window of protocol version 1. Its token only names the
+/// client and expires, and its requests are not signed, so such tokens are
+/// refused where an audience is required. They cannot be protected against
+/// replays either: tokens of the same client are identical within a second.
+fn authenticate_legacy_key(
+    token: &str,
+    state: &ServerState,
+    peer: Option<&PeerInfo>,
+    user_agent_header: &UserAgentHeader,
+    protocol: u32,
+) -> Result<WorkerAuthContext, AuthError> {
+    let token_data =
+        jsonwebtoken::dangerous::insecure_decode::<LegacyClaims>(token).map_err(|e| {
+            tracing::error!("Invalid JWT token: {e}");
+            AuthError::MalformedToken
+        })?;
+
+    let client_name = token_data.claims.sub;
+    let Some(client) = state.clients.iter().find(|e| e.name == client_name) else {
+        tracing::error!("Client {client_name} not found");
+        return Err(AuthError::InvalidCredentials);
+    };
+    check_certificate(peer, client)?;
+
+    if token_data.claims.jti.is_some() {
+        tracing::error!(
+            "Token of a current client {} was sent with protocol version {protocol}, which does not sign requests",
+            client.name
+        );
+        return Err(AuthError::InvalidCredentials);
+    }
+    if client.allowed_audiences.is_some() || state.server_id.is_some() {
+        tracing::error!(
+            "Client {}, version {} speaks protocol version {protocol}, whose tokens have no audience",
+            client.name,
+            user_agent_header.version
+        );
+        return Err(AuthError::InvalidAudience);
+    }
+
+    let mut validation = token_validation(Algorithm::EdDSA, state.clock_skew);
+    validation.validate_aud = false;
+    let now = Utc::now();
+    let (public_key, claims) =
+        verify_signature::<LegacyClaims>(token, client, state, &validation, now, |public_key| {
+            Some(public_key.key)
+        })?;
+
+    // The tokens have no issue time, so their lifetime is counted from now.
+    if let Err(e) = check_lifetime(
+        now.timestamp() as usize,
+        claims.exp,
+        state.max_token_lifetime,
+    ) {
+        tracing::error!("Token of client {} was rejected: {e}", client.name);
+        return Err(e);
+    }
+
+    if state.revocations.is_revoked(&public_key.fingerprint) {
+        tracing::error!(
+            "Client {} used revoked key {} ({})",
+            client.name,
+            public_key.label(),
+            public_key.fingerprint
+        );
+        return Err(AuthError::RevokedKey);
+    }
+
+    let token_id = URL_SAFE_NO_PAD.encode(Sha256::digest(token));
+
+    tracing::warn!(
+        "Client {}, version {} authenticated with key {} over deprecated protocol version {protocol}, \
+         its token can be replayed and its task requests are not signed; upgrade orosu-client",
+        client.name,
+        user_agent_header.version,
+        public_key.label()
+    );
+
+    Ok(WorkerAuthContext {
+        client: client.clone(),
+        key: None,
+        token_id,
+        scopes: None,
+        protocol,
+    })
+}
+
+/// Authenticates an OIDC token of a CI workflow and maps the workflow to the
+/// client whose `oidc` rules match it.
+async fn authenticate_oidc(
+    token: &str,
+    state: &ServerState,
+    peer: Option<&PeerInfo>,
+    user_agent_header: &UserAgentHeader,
+    protocol: u32,
+) -> Result<WorkerAuthContext, AuthError> {
+    let Some(oidc) = &state.oidc else {
+        tracing::error!("OIDC token received but OIDC authentication is not configured");
+        return Err(AuthError::OidcNotConfigured);
+    };
+    let now = Utc::now();
+    let (claims, request_key) = oidc.verify(token, now).await?;
+
+    let Some(client) = find_client(&state.clients, &claims) else {
+        tracing::error!(
+            "No client matches OIDC identity {} (repository {}, ref {}, environment {})",
+            claims.sub,
+            claims.repository,
+            claims.git_ref,
+            claims.environment.as_deref().unwrap_or("none")
+        );
+        return Err(AuthError::UnknownOidcIdentity);
+    };
+    check_certificate(peer, client)?;
+
+    let Some(token_id) = claims.jti else {
+        tracing::error!("OIDC token of {} has no jti claim", claims.sub);
+        return Err(AuthError::MissingClaim("jti".to_string()));
+    };
+    let expires_at = claims.exp + state.clock_skew as usize;
+    remember_token(state, &token_id, expires_at, now, &client.name)?;
+
+    if request_key.is_none() {
+        tracing::warn!(
+            "Client {} authenticated with an unbound OIDC token, its task requests are not signed",
+            client.name
+        );
+    }
+    tracing::info!(
+        "Client {}, version {} authenticated with OIDC identity {}",
+        client.name,
+        user_agent_header.version,
+        claims.sub
+    );
+
+    Ok(WorkerAuthContext {
+        client: client.clone(),
+        key: request_key,
+        token_id,
+        scopes: None,
+        protocol,
+    })
 }
 
 impl FromRequestParts<Arc<ServerState>> for WorkerAuthContext {
-    type Rejection = StatusCode;
+    type Rejection = AuthError;
 
     async fn from_request_parts(
         parts: &mut Parts,
@@ -145,3 +523,159 @@ impl FromRequestParts<Arc<ServerState>> for WorkerAuthContext {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::api::UserAgentHeader;
+    use crate::configuration::Configuration;
+    use crate::cryptography::{Claims, encode_public_key};
+    use crate::server::auth_error::AuthError;
+    use crate::server::{AuthContext, AuthScope, Server, ServerState};
+    use axum::extract::{FromRequestParts, Request};
+    use axum::http::header::{AUTHORIZATION, USER_AGENT};
+    use axum::http::{HeaderValue, StatusCode};
+    use axum::response::IntoResponse;
+    use ed25519_dalek::SigningKey;
+    use ed25519_dalek::pkcs8::EncodePrivateKey;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use serde::Serialize;
+    use std::sync::Arc;
+
+    fn server_state(key: &SigningKey, extra_configuration: &str) -> Arc<ServerState> {
+        let contents = format!(
+            r#"
+listen:
+  tcp: "127.0.0.1:8081"
+clients:
+  - name: "ci"
+    public_key: "{}"
+    scripts: []
+{extra_configuration}
+"#,
+            encode_public_key(&key.verifying_key())
+        );
+        let configuration: Configuration = serde_saphyr::from_str(&contents).unwrap();
+        Server::new(configuration).unwrap().state
+    }
+
+    fn token(key: &SigningKey, claims: &impl Serialize) -> String {
+        let der = key.to_pkcs8_der().unwrap();
+        let key = EncodingKey::from_ed_der(der.as_bytes());
+        jsonwebtoken::encode(&Header::new(Algorithm::EdDSA), claims, &key).unwrap()
+    }
+
+    async fn authenticate(
+        state: &Arc<ServerState>,
+        user_agent: HeaderValue,
+        token: &str,
+    ) -> Result<AuthContext, AuthError> {
+        let (mut parts, _) = Request::builder()
+            .header(USER_AGENT, user_agent)
+            .header(AUTHORIZATION, format!("Token {token}"))
+            .extension(AuthScope::Worker)
+            .body(())
+            .unwrap()
+            .into_parts();
+        AuthContext::from_request_parts(&mut parts, state).await
+    }
+
+    #[tokio::test]
+    async fn accepts_clients_released_before_protocol_versioning() {
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let state = server_state(&key, "");
+        let now = chrono::Utc::now().timestamp() as usize;
+        let legacy_user_agent = || HeaderValue::from_static("Orosu/0.5.1");
+
+        #[derive(Serialize)]
+        struct LegacyClaims {
+            sub: String,
+            exp: usize,
+        }
+        let legacy_token = token(
+            &key,
+            &LegacyClaims {
+                sub: String::from("ci"),
+                exp: now + 10,
+            },
+        );
+        let context = authenticate(&state, legacy_user_agent(), &legacy_token).await;
+        assert!(matches!(
+            context,
+            Ok(AuthContext::Worker(e)) if e.client.name == "ci" && e.key.is_none() && e.protocol == 1
+        ));
+        let context = authenticate(&state, legacy_user_agent(), &legacy_token).await;
+        assert!(matches!(context, Ok(AuthContext::Worker(_))));
+
+        let current_token = token(&key, &Claims::new("ci".into(), "orosu".into(), now, 10));
+        let error = authenticate(&state, legacy_user_agent(), &current_token)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, AuthError::InvalidCredentials));
+
+        let error = authenticate(&state, UserAgentHeader::default().into(), &legacy_token)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, AuthError::MalformedToken));
+
+        let context = authenticate(&state, UserAgentHeader::default().into(), &current_token).await;
+        assert!(matches!(
+            context,
+            Ok(AuthContext::Worker(e)) if e.key.is_some() && e.protocol == 2
+        ));
+
+        let error = authenticate(
+            &state,
+            HeaderValue::from_static("Orosu/9.0.0 (protocol 3-4)"),
+            &current_token,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.into_response().status(), StatusCode::UPGRADE_REQUIRED);
+
+        let state = server_state(&key, "server_id: \"orosu\"");
+        let error = authenticate(&state, legacy_user_agent(), &legacy_token)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, AuthError::InvalidAudience));
+    }
+
+    #[tokio::test]
+    async fn limits_only_authenticated_clients() {
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let forger = SigningKey::from_bytes(&[4; 32]);
+        let state = server_state(&key, "rate_limit:\n  max_attempts_per_client: 1");
+        let now = chrono::Utc::now().timestamp() as usize;
+        let claims = || Claims::new("ci".into(), "orosu".into(), now, 10);
+
+        for _ in 0..3 {
+            let error = authenticate(
+                &state,
+                UserAgentHeader::default().into(),
+                &token(&forger, &claims()),
+            )
+            .await
+            .unwrap_err();
+            assert!(matches!(error, AuthError::InvalidCredentials));
+        }
+        let context = authenticate(
+            &state,
+            UserAgentHeader::default().into(),
+            &token(&key, &claims()),
+        )
+        .await;
+        assert!(matches!(context, Ok(AuthContext::Worker(_))));
+
+        let error = authenticate(
+            &state,
+            UserAgentHeader::default().into(),
+            &token(&key, &claims()),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, AuthError::TooManyAttempts));
+        assert_eq!(
+            error.into_response().status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use crate::client::{Client, ClientPublicKey};
+use crate::cryptography::{fingerprint, parse_public_keys};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::VerifyingKey;
+use std::collections::HashMap;
+
+/// A client public key decoded at startup.
+pub struct AuthorizedKey {
+    pub source: ClientPublicKey,
+    pub comment: Option<String>,
+    pub key: VerifyingKey,
+    pub fingerprint: String,
+}
+
+impl AuthorizedKey {
+    fn load(source: ClientPublicKey) -> anyhow::Result<Vec<Self>> {
+        let key_source = source.source()?;
+        let contents = key_source.read()?;
+        let keys = parse_public_keys(&contents)
+            .with_context(|| format!("Invalid public key in {key_source}"))?;
+        Ok(keys
+            .into_iter()
+            .map(|public_key| Self {
+                source: source.clone(),
+                comment: public_key.comment,
+                fingerprint: fingerprint(&public_key.key),
+                key: public_key.key,
+            })
+            .collect())
+    }
+
+    pub fn label(&self) -> String {
+        match &self.comment {
+            Some(comment) => format!("{} ({comment})", self.source.label()),
+            None => self.source.label(),
+        }
+    }
+
+    pub fn is_valid_at(&self, now: DateTime<Utc>) -> bool {
+        self.source.is_valid_at(now)
+    }
+}
+
+/// Decodes the keys of every client, keyed by client name.
+pub fn load_authorized_keys(
+    clients: &[Client],
+) -> anyhow::Result<HashMap<String, Vec<AuthorizedKey>>> {
+    let mut result = HashMap::new();
+    for client in clients {
+        client.validate()?;
+        let keys = client
+            .public_keys()
+            .into_iter()
+            .map(AuthorizedKey::load)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map(|keys| keys.into_iter().flatten().collect::<Vec<_>>())
+            .with_context(|| format!("Cannot load keys of client {}", client.name))?;
+        result.insert(client.name.clone(), keys);
+    }
+    Ok(result)
+}
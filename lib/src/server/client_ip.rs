@@ -0,0 +1,229 @@
+use crate::configuration::ClientIpHeader;
+use crate::server::peer::PeerInfo;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use cidr::IpCidr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Address of the client that made the request, after resolving forwarded
+/// headers of trusted proxies. Absent if the listener has no peer address and
+/// no trusted header named the client, e.g. on a Unix socket.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddress(pub IpAddr);
+
+/// Proxies trusted by default: a reverse proxy on the same host.
+pub fn default_trusted_proxies() -> Vec<IpCidr> {
+    vec![
+        IpCidr::new_host(IpAddr::from([127, 0, 0, 1])),
+        IpCidr::new_host(IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])),
+    ]
+}
+
+/// Determines the client address from the TCP peer and, if the peer is a
+/// trusted proxy, from the configured forwarding header.
+pub struct ClientIpResolver {
+    header: ClientIpHeader,
+    trusted_proxies: Vec<IpCidr>,
+}
+
+impl ClientIpResolver {
+    pub fn new(header: ClientIpHeader, trusted_proxies: Vec<IpCidr>) -> Self {
+        Self {
+            header,
+            trusted_proxies,
+        }
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// The client address for a request from `peer`, which is `None` for
+    /// Unix sockets. Only a local proxy can connect to a socket, so it is
+    /// trusted like a listed proxy. A trusted peer sending no header is the
+    /// client itself, while a header that names no valid address leaves the
+    /// client unknown.
+    pub fn resolve(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        if let Some(peer) = peer
+            && !self.is_trusted(&peer)
+        {
+            return Some(peer);
+        }
+        if !headers.contains_key(self.header_name()) {
+            return peer;
+        }
+        self.forwarded_ip(headers)
+    }
+
+    fn header_name(&self) -> &'static str {
+        match self.header {
+            ClientIpHeader::XForwardedFor => "x-forwarded-for",
+            ClientIpHeader::XRealIp => "x-real-ip",
+            ClientIpHeader::Forwarded => "forwarded",
+            ClientIpHeader::CfConnectingIp => "cf-connecting-ip",
+        }
+    }
+
+    fn forwarded_ip(&self, headers: &HeaderMap) -> Option<IpAddr> {
+        let name = self.header_name();
+        match self.header {
+            ClientIpHeader::XForwardedFor => self.first_untrusted(
+                header_values(headers, name)
+                    .flat_map(|value| value.split(','))
+                    .map(|e| e.trim().parse().ok())
+                    .collect(),
+            ),
+            ClientIpHeader::Forwarded => self.first_untrusted(
+                header_values(headers, name)
+                    .flat_map(|value| value.split(','))
+                    .filter_map(forwarded_for)
+                    .collect(),
+            ),
+            ClientIpHeader::XRealIp | ClientIpHeader::CfConnectingIp => single_ip(headers, name),
+        }
+    }
+
+    /// Walks a proxy chain from the nearest hop and returns the first address
+    /// that is not a trusted proxy. Anything before an unparsable hop could
+    /// have been forged by the client, so the walk stops there.
+    fn first_untrusted(&self, chain: Vec<Option<IpAddr>>) -> Option<IpAddr> {
+        let mut client = None;
+        for hop in chain.into_iter().rev() {
+            let ip = hop?;
+            client = Some(ip);
+            if !self.is_trusted(&ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+}
+
+fn single_ip(headers: &HeaderMap, name: &str) -> Option<IpAddr> {
+    let mut values = header_values(headers, name);
+    let value = values.next()?;
+    if values.next().is_some() {
+        return None;
+    }
+    value.trim().parse().ok()
+}
+
+/// The `for` parameter of a `Forwarded` element, e.g. `for=192.0.2.60`,
+/// `for="[2001:db8::1]:4711"`. `None` for missing or obfuscated identifiers.
+fn forwarded_for(element: &str) -> Option<Option<IpAddr>> {
+    let value = element.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        key.eq_ignore_ascii_case("for").then_some(value)
+    })?;
+    let value = value.trim_matches('"');
+    let ip = value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|e| e.ip()))
+        .or_else(|| {
+            value
+                .strip_prefix('[')
+                .and_then(|e| e.strip_suffix(']'))
+                .and_then(|e| e.parse().ok())
+        });
+    Some(ip)
+}
+
+/// Resolves the client address once and stores it for the later layers and
+/// handlers.
+pub async fn client_ip_layer(
+    State(resolver): State<Arc<ClientIpResolver>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<PeerInfo>>()
+        .and_then(|ConnectInfo(peer)| peer.address)
+        .map(|address| address.ip());
+    if let Some(ip) = resolver.resolve(peer, request.headers()) {
+        request.extensions_mut().insert(ClientAddress(ip));
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::ClientIpHeader;
+    use crate::server::client_ip::{ClientIpResolver, default_trusted_proxies};
+    use axum::http::HeaderMap;
+    use std::net::IpAddr;
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let resolver =
+            ClientIpResolver::new(ClientIpHeader::XForwardedFor, default_trusted_proxies());
+        let headers = headers("x-forwarded-for", "10.0.0.1");
+        assert_eq!(
+            resolver.resolve(Some(ip("203.0.113.7")), &headers),
+            Some(ip("203.0.113.7"))
+        );
+        assert_eq!(
+            resolver.resolve(Some(ip("127.0.0.1")), &headers),
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(resolver.resolve(None, &headers), Some(ip("10.0.0.1")));
+        assert_eq!(resolver.resolve(None, &HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn skips_trusted_proxies_in_chain() {
+        let trusted = vec![
+            "127.0.0.1/32".parse().unwrap(),
+            "10.0.0.0/8".parse().unwrap(),
+        ];
+        let resolver = ClientIpResolver::new(ClientIpHeader::XForwardedFor, trusted);
+        let headers = headers("x-forwarded-for", "10.9.9.9, 198.51.100.1, 10.0.0.2");
+        assert_eq!(
+            resolver.resolve(Some(ip("127.0.0.1")), &headers),
+            Some(ip("198.51.100.1"))
+        );
+
+        let forged = self::headers("x-forwarded-for", "198.51.100.1, garbage, 10.0.0.2");
+        assert_eq!(resolver.resolve(Some(ip("127.0.0.1")), &forged), None);
+    }
+
+    #[test]
+    fn reads_other_headers() {
+        let peer = Some(ip("127.0.0.1"));
+        let forwarded = ClientIpResolver::new(ClientIpHeader::Forwarded, default_trusted_proxies());
+        let headers = headers(
+            "forwarded",
+            r#"for="[2001:db8::1]:4711";proto=https, for=127.0.0.1"#,
+        );
+        assert_eq!(forwarded.resolve(peer, &headers), Some(ip("2001:db8::1")));
+
+        let real_ip = ClientIpResolver::new(ClientIpHeader::XRealIp, default_trusted_proxies());
+        let headers = self::headers("x-real-ip", "192.0.2.60");
+        assert_eq!(real_ip.resolve(peer, &headers), Some(ip("192.0.2.60")));
+
+        let cloudflare =
+            ClientIpResolver::new(ClientIpHeader::CfConnectingIp, default_trusted_proxies());
+        assert_eq!(cloudflare.resolve(peer, &headers), peer);
+    }
+}
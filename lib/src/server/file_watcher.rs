@@ -0,0 +1,32 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+fn file_version(path: &PathBuf) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Polls `path` and calls `on_change` whenever its modification time or size
+/// changes. Symlinks are followed, so replacing a symlink target is noticed.
+pub fn watch_file<F>(path: PathBuf, on_change: F) -> JoinHandle<()>
+where
+    F: Fn() + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut version = file_version(&path);
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let current = file_version(&path);
+            if current != version {
+                tracing::debug!("File {} has changed", path.display());
+                version = current;
+                on_change();
+            }
+        }
+    })
+}
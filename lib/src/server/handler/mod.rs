@@ -1,3 +1,4 @@
+mod task_error;
 mod tasks;
 
 pub struct TasksHandler;
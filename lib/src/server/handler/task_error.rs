@@ -0,0 +1,147 @@
+use crate::api::ServerErrorResponse;
+use crate::server::attachment::AttachmentError;
+use std::fmt::{Display, Formatter};
+use tokio::task::JoinError;
+
+/// Reason a task request failed. The message is sent to the client, so it
+/// names the failing step without exposing server paths.
+#[derive(Debug)]
+pub enum TaskError {
+    /// The client went away, nobody is left to tell.
+    Disconnected,
+    MalformedRequest(String),
+    InvalidSignature,
+    OutsideScopes {
+        script: String,
+    },
+    ScriptNotFound {
+        script: String,
+    },
+    AddressNotAllowed {
+        script: String,
+    },
+    AttachmentTooLarge {
+        size: u64,
+        limit: u64,
+    },
+    UnexpectedChunk {
+        offset: usize,
+        expected: usize,
+    },
+    AttachmentOverflow {
+        size: usize,
+    },
+    HashMismatch,
+    Attachment(AttachmentError),
+    Storage(std::io::Error),
+    /// The script could not be started, the cause is only logged as it can
+    /// name server paths.
+    Launch,
+    Aborted(JoinError),
+}
+
+impl Display for TaskError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskError::Disconnected => write!(f, "client disconnected"),
+            TaskError::MalformedRequest(reason) => write!(f, "malformed request: {reason}"),
+            TaskError::InvalidSignature => write!(f, "task request signature is invalid"),
+            TaskError::OutsideScopes { script } => {
+                write!(f, "script {script} is outside of the token scopes")
+            }
+            TaskError::ScriptNotFound { script } => {
+                write!(f, "script {script} is not configured for this client")
+            }
+            TaskError::AddressNotAllowed { script } => {
+                write!(f, "script {script} cannot be run from this address")
+            }
+            TaskError::AttachmentTooLarge { size, limit } => write!(
+                f,
+                "attachment of {size} bytes exceeds the limit of {limit} bytes"
+            ),
+            TaskError::UnexpectedChunk { offset, expected } => write!(
+                f,
+                "attachment chunk has offset {offset}, expected {expected}"
+            ),
+            TaskError::AttachmentOverflow { size } => {
+                write!(f, "attachment is larger than the declared {size} bytes")
+            }
+            TaskError::HashMismatch => write!(f, "attachment hash does not match its contents"),
+            TaskError::Attachment(e) => write!(f, "{e}"),
+            TaskError::Storage(e) => write!(f, "cannot store the attachment: {e}"),
+            TaskError::Launch => write!(f, "cannot start the script"),
+            TaskError::Aborted(_) => write!(f, "task was aborted by the server"),
+        }
+    }
+}
+
+impl From<TaskError> for ServerErrorResponse {
+    fn from(value: TaskError) -> Self {
+        let message = Some(value.to_string());
+        match value {
+            TaskError::Disconnected | TaskError::MalformedRequest(_) => {
+                ServerErrorResponse::InvalidRequest { message }
+            }
+            TaskError::InvalidSignature => ServerErrorResponse::InvalidSignature { message },
+            TaskError::OutsideScopes { .. } | TaskError::AddressNotAllowed { .. } => {
+                ServerErrorResponse::Forbidden { message }
+            }
+            TaskError::ScriptNotFound { .. } => ServerErrorResponse::ScriptNotFound { message },
+            TaskError::AttachmentTooLarge { .. }
+            | TaskError::Attachment(AttachmentError::TooLarge(_)) => {
+                ServerErrorResponse::AttachmentTooLarge { message }
+            }
+            TaskError::UnexpectedChunk { .. }
+            | TaskError::AttachmentOverflow { .. }
+            | TaskError::Attachment(AttachmentError::Invalid(_)) => {
+                ServerErrorResponse::InvalidAttachment { message }
+            }
+            TaskError::Attachment(AttachmentError::Io(_))
+            | TaskError::Storage(_)
+            | TaskError::Launch => ServerErrorResponse::CannotLaunchScript { message },
+            TaskError::HashMismatch => ServerErrorResponse::HashMismatch { message },
+            TaskError::Aborted(_) => ServerErrorResponse::Unknown { message },
+        }
+    }
+}
+
+impl From<AttachmentError> for TaskError {
+    fn from(value: AttachmentError) -> Self {
+        TaskError::Attachment(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::ServerErrorResponse;
+    use crate::server::attachment::AttachmentError;
+    use crate::server::handler::task_error::TaskError;
+
+    #[test]
+    fn maps_errors_to_responses_with_message() {
+        let response = ServerErrorResponse::from(TaskError::Attachment(AttachmentError::Invalid(
+            String::from("entry link is a symlink"),
+        )));
+        assert!(matches!(
+            response,
+            ServerErrorResponse::InvalidAttachment { .. }
+        ));
+        assert_eq!(response.message(), Some("entry link is a symlink"));
+
+        let storage = std::io::Error::from(std::io::ErrorKind::StorageFull);
+        let response = ServerErrorResponse::from(TaskError::Storage(storage));
+        assert!(matches!(
+            response,
+            ServerErrorResponse::CannotLaunchScript { .. }
+        ));
+        assert!(
+            response
+                .message()
+                .unwrap()
+                .contains("cannot store the attachment")
+        );
+
+        let response = ServerErrorResponse::from(TaskError::Launch);
+        assert_eq!(response.message(), Some("cannot start the script"));
+    }
+}
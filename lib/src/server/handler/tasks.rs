@@ -1,233 +1,151 @@
 use crate::api::envelopes::{
-    RequestEnvelope, TaskEventResponseEnvelope, TaskLaunchStatusResponseEnvelope,
+    RequestEnvelope, TaskEventResponseEnvelope, TaskLaunchRequestEnvelope,
+    TaskLaunchStatusResponseEnvelope,
 };
-use crate::api::file_chunk::FileChunk;
-use crate::api::{ServerErrorResponse, ServerTaskNotification, StartTaskRequest, TaskLaunchStatus};
-use crate::client::Client;
-use crate::server::AuthContext;
+use crate::api::file_chunk::{AttachmentDigest, FileChunk};
+use crate::api::{FileAttachment, ServerTaskNotification, TaskLaunchStatus};
+use crate::server::attachment;
+use crate::server::attachment::AttachmentLimits;
+use crate::server::client_ip::ClientAddress;
 use crate::server::handler::TasksHandler;
+use crate::server::handler::task_error::TaskError;
+use crate::server::peer::PeerInfo;
+use crate::server::{AuthContext, WorkerAuthContext};
 use crate::tasks::TaskLaunchResult;
 use crate::tasks::task::Task;
+use axum::Extension;
 use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{ConnectInfo, FromRequestParts, Request, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, WebSocketUpgrade};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
-use axum_client_ip::ClientIp;
+use cidr::IpCidr;
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
-use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
-use std::net::SocketAddr;
+use serde::de::DeserializeOwned;
+use std::io::Write;
+use std::net::IpAddr;
 use std::time::Duration;
-use tempfile::{NamedTempFile, TempDir};
+use tempfile::NamedTempFile;
 use tokio::time::timeout;
-use zip::ZipArchive;
 
 impl TasksHandler {
     pub async fn attach(
-        ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+        ConnectInfo(peer): ConnectInfo<PeerInfo>,
+        client_address: Option<Extension<ClientAddress>>,
         auth_context: AuthContext,
         ws: WebSocketUpgrade,
-        request: Request,
     ) -> impl IntoResponse {
-        let client = match auth_context {
-            AuthContext::Worker(worker_auth_context) => worker_auth_context.client,
-        };
-
-        let (mut parts, _) = request.into_parts();
+        let AuthContext::Worker(worker_auth_context) = auth_context;
+        let client = &worker_auth_context.client;
+        let ip = client_address.map(|Extension(ClientAddress(ip))| ip);
 
-        let ip = ClientIp::from_request_parts(&mut parts, &())
-            .await
-            .map(|e| e.0)
-            .unwrap_or_else(|_| remote_addr.ip());
-
-        if let Some(whitelist) = &client.whitelisted_ips
-            && !whitelist.iter().any(|cidr| cidr.contains(&ip))
-        {
-            tracing::warn!("Client {} is not whitelisted for {}", ip, client.name);
+        let owner = format!("client {}", client.name);
+        if !ip_allowed(ip, &client.whitelisted_ips, &client.blacklisted_ips, &owner) {
             return StatusCode::FORBIDDEN.into_response();
-        };
+        }
 
-        if let Some(blacklist) = &client.blacklisted_ips
-            && blacklist.iter().any(|cidr| cidr.contains(&ip))
-        {
-            tracing::warn!("Client {} is blacklisted for {}", ip, client.name);
-            return StatusCode::FORBIDDEN.into_response();
+        if client.restricts_peer_credentials() {
+            let Some(credentials) = peer.credentials else {
+                tracing::warn!(
+                    "Peer credentials are unknown, cannot check the allowed users of {}",
+                    client.name
+                );
+                return StatusCode::FORBIDDEN.into_response();
+            };
+            if !client.accepts_peer_credentials(credentials.uid, credentials.gid) {
+                tracing::warn!(
+                    "Socket peer with uid {} and gid {} is not allowed for {}",
+                    credentials.uid,
+                    credentials.gid,
+                    client.name
+                );
+                return StatusCode::FORBIDDEN.into_response();
+            }
         }
 
-        ws.on_upgrade(move |socket| handle_task_run_output(socket, client))
+        ws.on_upgrade(move |socket| handle_task_run_output(socket, worker_auth_context, ip))
     }
 }
 
-async fn handle_task_run_output(mut socket: WebSocket, client: Client) {
-    let Some(task_message_result) = socket.recv().await else {
-        tracing::info!("Client disconnected");
-        _ = socket.send(Message::Close(None)).await;
-        return;
-    };
-    let Ok(task_message) = task_message_result else {
-        tracing::error!("Cannot receive task message");
-        _ = socket.send(Message::Close(None)).await;
-        return;
-    };
-
-    let Message::Binary(start_task_message) = task_message else {
-        tracing::error!("Cannot deserialize task message");
-        _ = socket.send(Message::Close(None)).await;
-        return;
-    };
-
-    let Ok(start_task_message_payload) =
-        serde_json::from_slice::<RequestEnvelope<StartTaskRequest>>(&start_task_message)
-    else {
-        tracing::error!("Cannot deserialize task message from bytes");
-        _ = socket.send(Message::Close(None)).await;
-        return;
-    };
-
-    tracing::info!("Received task message: {:?}", start_task_message_payload);
-
-    let (mut sender, mut receiver) = socket.split();
-
-    let arguments = start_task_message_payload.body.arguments;
-    let attachment = start_task_message_payload.body.file;
-    let script_name = start_task_message_payload.body.script_name;
-
-    let script = client.scripts.iter().find(|e| e.name == script_name);
-
-    let Some(script) = script else {
-        tracing::error!("Script {} not found", script_name);
-        let error_message = TaskLaunchStatusResponseEnvelope::Failure {
-            error: ServerErrorResponse::ScriptNotFound,
+/// Checks the client address against an IP whitelist and blacklist of
+/// `owner`. An unknown address only passes if there is no whitelist.
+fn ip_allowed(
+    ip: Option<IpAddr>,
+    whitelist: &Option<Vec<IpCidr>>,
+    blacklist: &Option<Vec<IpCidr>>,
+    owner: &str,
+) -> bool {
+    if let Some(whitelist) = whitelist {
+        let Some(ip) = ip else {
+            tracing::warn!("Client address is unknown, cannot check the whitelist of {owner}");
+            return false;
         };
-        _ = sender.send(Message::Binary(error_message.into())).await;
-        _ = sender.send(Message::Close(None)).await;
-        return;
-    };
-
-    let attachment = match attachment {
-        None => None,
-        Some(attachment) => {
-            let mut output = NamedTempFile::with_suffix(".zip").unwrap();
-            let mut offset = 0;
-            let size = attachment.size;
-            let hash = attachment.hash;
-            let mut hasher = md5::Context::new();
-            while offset < size {
-                let chunk_message = TaskLaunchStatusResponseEnvelope::Success {
-                    body: TaskLaunchStatus::AwaitingFiles { offset },
-                };
-                _ = sender.send(Message::Binary(chunk_message.into())).await;
-                let Some(response) = receiver.next().await else {
-                    tracing::error!("Client disconnected during file transfer");
-                    _ = sender.send(Message::Close(None)).await;
-                    return;
-                };
-                let Ok(response) = response else {
-                    tracing::error!("Cannot deserialize file chunk message");
-                    _ = sender.send(Message::Close(None)).await;
-                    return;
-                };
-                let Message::Binary(chunk) = response else {
-                    tracing::error!("Cannot deserialize file chunk message");
-                    _ = sender.send(Message::Close(None)).await;
-                    return;
-                };
-                let Ok(chunk) = serde_json::from_slice::<RequestEnvelope<FileChunk>>(&chunk) else {
-                    tracing::error!("Cannot deserialize file chunk message from bytes");
-                    _ = sender.send(Message::Close(None)).await;
-                    return;
-                };
-                let body = chunk.body;
-                let chunk_offset = body.offset;
-                if chunk_offset != offset {
-                    tracing::error!("Unexpected chunk offset {chunk_offset}, expected {offset}");
-                    return;
-                }
-                output.write_all(&body.data).unwrap();
-                hasher.consume(&body.data);
-                offset += body.data.len();
-                tracing::debug!("Received attachment chunk with offset {chunk_offset}");
-            }
-            output.seek(SeekFrom::Start(0)).unwrap();
-            tracing::debug!(
-                "Finished attached file, saved into {}",
-                output.path().display()
-            );
-
-            let computed_hash = hasher.finalize().0.to_vec();
-            if computed_hash != hash {
-                tracing::error!("File hash mismatch");
-                let error_message = TaskLaunchStatusResponseEnvelope::Failure {
-                    error: ServerErrorResponse::CannotLaunchScript,
-                };
-                _ = sender.send(Message::Binary(error_message.into())).await;
-                _ = sender.send(Message::Close(None)).await;
-                return;
-            }
-            tracing::debug!("File hash validated successfully");
-
-            Some(output)
+        if !whitelist.iter().any(|cidr| cidr.contains(&ip)) {
+            tracing::warn!("Client {ip} is not whitelisted for {owner}");
+            return false;
         }
-    };
-
-    let directory = match attachment {
-        None => None,
-        Some(mut file) => {
-            let directory = TempDir::new().unwrap();
-            tracing::debug!(
-                "Created temporary directory for attached files: {}",
-                directory.path().display()
-            );
-
-            let mut archive = ZipArchive::new(&mut file).unwrap();
-            for i in 0..archive.len() {
-                let mut entry = archive.by_index(i).unwrap();
-                let output_path = directory.path().join(entry.name());
+    }
+    if let Some(blacklist) = blacklist
+        && let Some(ip) = ip
+        && blacklist.iter().any(|cidr| cidr.contains(&ip))
+    {
+        tracing::warn!("Client {ip} is blacklisted for {owner}");
+        return false;
+    }
+    true
+}
 
-                if entry.is_dir() {
-                    std::fs::create_dir_all(&output_path).unwrap();
-                } else {
-                    if let Some(parent) = output_path.parent() {
-                        std::fs::create_dir_all(parent).unwrap();
-                    }
-                    let mut outfile = File::create(&output_path).unwrap();
-                    std::io::copy(&mut entry, &mut outfile).unwrap();
-                }
-                tracing::debug!("Extracted: {}", output_path.display());
-            }
-            tracing::debug!(
-                "Successfully extracted archive to {}",
-                directory.path().display()
-            );
+/// A started script with the channels to follow it.
+struct LaunchedTask {
+    script_name: String,
+    task: Task,
+    result: TaskLaunchResult,
+}
 
-            Some(directory)
+async fn handle_task_run_output(
+    socket: WebSocket,
+    auth_context: WorkerAuthContext,
+    ip: Option<IpAddr>,
+) {
+    let protocol = auth_context.protocol;
+    let (mut sender, mut receiver) = socket.split();
+    let launched = match launch_task(&mut sender, &mut receiver, &auth_context, ip).await {
+        Ok(launched) => launched,
+        Err(TaskError::Disconnected) => {
+            tracing::info!("Client disconnected");
+            _ = sender.send(Message::Close(None)).await;
+            return;
         }
-    };
-
-    let task = Task::create(script.clone());
-
-    let TaskLaunchResult {
-        created_on,
-        handler,
-    } = match task.run(arguments, directory).await {
-        Ok(task) => task,
         Err(e) => {
-            tracing::error!("Unable to launch script {}: {:?}", script_name, e);
-            let error_message = TaskLaunchStatusResponseEnvelope::Failure {
-                error: ServerErrorResponse::CannotLaunchScript,
-            };
-            _ = sender.send(Message::Binary(error_message.into())).await;
+            tracing::error!(
+                "Task request of client {} failed: {e}",
+                auth_context.client.name
+            );
+            let error_message = TaskLaunchStatusResponseEnvelope::Failure { error: e.into() };
+            _ = sender
+                .send(Message::Binary(error_message.encode(protocol)))
+                .await;
             _ = sender.send(Message::Close(None)).await;
             return;
         }
     };
+    let LaunchedTask {
+        script_name,
+        task,
+        result: TaskLaunchResult {
+            created_on,
+            handler,
+        },
+    } = launched;
 
     let created_message = TaskLaunchStatusResponseEnvelope::Success {
         body: TaskLaunchStatus::Launched {
             started_on: created_on,
         },
     };
-    _ = sender.send(Message::Binary(created_message.into())).await;
+    _ = sender
+        .send(Message::Binary(created_message.encode(protocol)))
+        .await;
 
     tracing::info!("Starting task for script {}", script_name);
 
@@ -243,7 +161,7 @@ async fn handle_task_run_output(mut socket: WebSocket, client: Client) {
                         let message = TaskEventResponseEnvelope::Success {
                             body: ServerTaskNotification::Output(event),
                         };
-                        if let Err(e) = sender.send(Message::Binary(message.into())).await {
+                        if let Err(e) = sender.send(Message::Binary(message.encode(protocol))).await {
                             tracing::error!("Cannot send real-time event: {:?}", e);
                             break None;
                         };
@@ -253,7 +171,18 @@ async fn handle_task_run_output(mut socket: WebSocket, client: Client) {
                     }
                 }
             }
-            res = &mut handler_fuse => break Some(res.unwrap()),
+            res = &mut handler_fuse => match res {
+                Ok(exit_code) => break Some(exit_code),
+                Err(e) => {
+                    tracing::error!("Task of script {} failed: {:?}", script_name, e);
+                    let message = TaskEventResponseEnvelope::Failure {
+                        error: TaskError::Aborted(e).into(),
+                    };
+                    _ = sender.send(Message::Binary(message.encode(protocol))).await;
+                    _ = sender.send(Message::Close(None)).await;
+                    return;
+                }
+            },
         }
     };
     let Some(exit_code) = exit_code else {
@@ -268,7 +197,7 @@ async fn handle_task_run_output(mut socket: WebSocket, client: Client) {
     let message = TaskEventResponseEnvelope::Success {
         body: ServerTaskNotification::ExitCode(exit_code),
     };
-    if let Err(e) = sender.send(Message::Binary(message.into())).await {
+    if let Err(e) = sender.send(Message::Binary(message.encode(protocol))).await {
         tracing::error!("Cannot send exit-code event: {:?}", e);
     };
 
@@ -305,3 +234,150 @@ async fn handle_task_run_output(mut socket: WebSocket, client: Client) {
         tracing::warn!("Client did not close connection in time");
     }
 }
+
+/// Reads the task request, checks that the connection may run the script,
+/// receives and extracts the attachment and starts the script.
+async fn launch_task(
+    sender: &mut SplitSink<WebSocket, Message>,
+    receiver: &mut SplitStream<WebSocket>,
+    auth_context: &WorkerAuthContext,
+    ip: Option<IpAddr>,
+) -> Result<LaunchedTask, TaskError> {
+    let client = &auth_context.client;
+    let start_task_message_payload: TaskLaunchRequestEnvelope = receive(receiver).await?;
+
+    tracing::info!("Received task message: {:?}", start_task_message_payload);
+
+    if let Some(key) = &auth_context.key
+        && !start_task_message_payload.verify(&auth_context.token_id, key)
+    {
+        return Err(TaskError::InvalidSignature);
+    }
+
+    let arguments = start_task_message_payload.body.arguments;
+    let attachment = start_task_message_payload.body.file;
+    let script_name = start_task_message_payload.body.script_name;
+
+    if let Some(scopes) = &auth_context.scopes
+        && !scopes.contains(&script_name)
+    {
+        return Err(TaskError::OutsideScopes {
+            script: script_name,
+        });
+    }
+
+    let Some(script) = client.scripts.iter().find(|e| e.name == script_name) else {
+        return Err(TaskError::ScriptNotFound {
+            script: script_name,
+        });
+    };
+
+    let owner = format!("script {} of client {}", script.name, client.name);
+    if !ip_allowed(ip, &script.whitelisted_ips, &script.blacklisted_ips, &owner) {
+        return Err(TaskError::AddressNotAllowed {
+            script: script_name,
+        });
+    }
+
+    let limits = AttachmentLimits::of(client, script);
+    let directory = match attachment {
+        None => None,
+        Some(attachment) => {
+            let file =
+                receive_attachment(sender, receiver, attachment, &limits, auth_context.protocol)
+                    .await?;
+            let extraction =
+                tokio::task::spawn_blocking(move || attachment::extract(file, &limits));
+            Some(extraction.await.map_err(TaskError::Aborted)??)
+        }
+    };
+
+    let task = Task::create(script.clone());
+    let result = task.run(arguments, directory).await.map_err(|e| {
+        tracing::error!("Cannot start script {script_name}: {e:#}");
+        TaskError::Launch
+    })?;
+    Ok(LaunchedTask {
+        script_name,
+        task,
+        result,
+    })
+}
+
+/// Requests the attachment chunk by chunk and stores it in a temporary file.
+async fn receive_attachment(
+    sender: &mut SplitSink<WebSocket, Message>,
+    receiver: &mut SplitStream<WebSocket>,
+    attachment: FileAttachment,
+    limits: &AttachmentLimits,
+    protocol: u32,
+) -> Result<NamedTempFile, TaskError> {
+    let size = attachment.size;
+    if size as u64 > limits.max_attachment_size {
+        return Err(TaskError::AttachmentTooLarge {
+            size: size as u64,
+            limit: limits.max_attachment_size,
+        });
+    }
+    let mut output = NamedTempFile::with_suffix(".zip").map_err(TaskError::Storage)?;
+    let mut offset = 0;
+    let mut digest = AttachmentDigest::new(protocol);
+    while offset < size {
+        let chunk_message = TaskLaunchStatusResponseEnvelope::Success {
+            body: TaskLaunchStatus::AwaitingFiles { offset },
+        };
+        _ = sender
+            .send(Message::Binary(chunk_message.encode(protocol)))
+            .await;
+        let chunk: RequestEnvelope<FileChunk> = receive(receiver).await?;
+        let body = chunk.body;
+        let chunk_offset = body.offset;
+        if chunk_offset != offset {
+            return Err(TaskError::UnexpectedChunk {
+                offset: chunk_offset,
+                expected: offset,
+            });
+        }
+        if offset + body.data.len() > size {
+            return Err(TaskError::AttachmentOverflow { size });
+        }
+        output.write_all(&body.data).map_err(TaskError::Storage)?;
+        digest.update(&body.data);
+        offset += body.data.len();
+        tracing::debug!("Received attachment chunk with offset {chunk_offset}");
+    }
+    tracing::debug!(
+        "Finished attached file, saved into {}",
+        output.path().display()
+    );
+
+    if digest.finalize() != attachment.hash {
+        return Err(TaskError::HashMismatch);
+    }
+    tracing::debug!("File hash validated successfully");
+    Ok(output)
+}
+
+/// Waits for the next binary message of the client and parses it.
+async fn receive<T: DeserializeOwned>(
+    receiver: &mut SplitStream<WebSocket>,
+) -> Result<T, TaskError> {
+    let message = match receiver.next().await {
+        Some(Ok(message)) => message,
+        Some(Err(e)) => {
+            tracing::error!("Cannot receive message: {e}");
+            return Err(TaskError::Disconnected);
+        }
+        None => return Err(TaskError::Disconnected),
+    };
+    let message = match message {
+        Message::Binary(message) => message,
+        Message::Close(_) => return Err(TaskError::Disconnected),
+        _ => {
+            return Err(TaskError::MalformedRequest(String::from(
+                "expected a binary message",
+            )));
+        }
+    };
+    serde_json::from_slice(&message).map_err(|e| TaskError::MalformedRequest(e.to_string()))
+}
@@ -1,29 +1,74 @@
+use crate::api::protocol::{PROTOCOL_HEADER, ProtocolRange};
 use crate::client::Client;
-use crate::configuration::ListenConfiguration;
+use crate::configuration::{Configuration, ListenConfiguration};
+use crate::server::authorized_keys::{AuthorizedKey, load_authorized_keys};
+use crate::server::client_ip::{
+    ClientAddress, ClientIpResolver, client_ip_layer, default_trusted_proxies,
+};
 use crate::server::handler::TasksHandler;
+use crate::server::nonce_cache::NonceCache;
+use crate::server::oidc::{JWKS_URL_REFRESH_INTERVAL, OidcVerifier};
+use crate::server::peer::PeerInfo;
+use crate::server::rate_limit::{RateLimiter, metrics_handler, rate_limit_layer};
+use crate::server::revocation::RevocationList;
+use crate::server::tls::{CertificateResolver, TlsListener};
+use crate::server::token_validation::{DEFAULT_CLOCK_SKEW, DEFAULT_MAX_TOKEN_LIFETIME};
 use anyhow::Context;
-use axum::extract::{ConnectInfo, FromRequestParts, Request, State};
-use axum::http::StatusCode;
+use axum::Extension;
+use axum::extract::{Request, State};
+use axum::http::{HeaderValue, StatusCode};
 use axum::middleware;
 use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
 use axum::routing::get;
-use axum_client_ip::{ClientIp, ClientIpSource};
+use chrono::Utc;
 use cidr::IpCidr;
-use std::net::SocketAddr;
+use ed25519_dalek::VerifyingKey;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tower_http::trace::TraceLayer;
 
+mod attachment;
+mod auth_error;
 mod auth_scope;
+mod authorized_keys;
+mod client_ip;
+mod file_watcher;
 mod handler;
+mod nonce_cache;
+mod oidc;
+mod peer;
+mod rate_limit;
+mod revocation;
+#[cfg(unix)]
+mod socket;
+mod tls;
+mod token_validation;
+
+/// Upper bound of token identifiers remembered for replay protection.
+const NONCE_CACHE_CAPACITY: usize = 65536;
+
+/// How often expired rate limit counters and bans are dropped.
+const RATE_LIMIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct ServerState {
+    server_id: Option<String>,
+    clock_skew: u64,
+    max_token_lifetime: u64,
     clients: Vec<Client>,
+    keys: HashMap<String, Vec<AuthorizedKey>>,
+    revocations: RevocationList,
+    nonces: NonceCache,
+    oidc: Option<OidcVerifier>,
+    rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 pub struct Server {
     listen: ListenConfiguration,
     state: Arc<ServerState>,
+    client_ip: Arc<ClientIpResolver>,
+    rate_limiter: Option<Arc<RateLimiter>>,
     whitelist: Option<Vec<IpCidr>>,
     blacklist: Option<Vec<IpCidr>>,
 }
@@ -36,6 +81,15 @@ pub enum AuthScope {
 #[derive(Clone, Debug)]
 pub struct WorkerAuthContext {
     pub client: Client,
+    /// Key that signs task requests: the client key or the request key an
+    /// OIDC token is bound to. Absent only for unbound OIDC tokens accepted
+    /// through `allow_unbound_tokens`.
+    pub key: Option<VerifyingKey>,
+    pub token_id: String,
+    /// Scripts the connection may run, all scripts of the client when absent.
+    pub scopes: Option<Vec<String>>,
+    /// Protocol version negotiated with the client.
+    pub protocol: u32,
 }
 
 #[derive(Clone, Debug)]
@@ -44,24 +98,112 @@ pub enum AuthContext {
 }
 
 impl Server {
-    pub fn new(
-        listen: ListenConfiguration,
-        whitelist: Option<Vec<IpCidr>>,
-        blacklist: Option<Vec<IpCidr>>,
-        clients: Vec<Client>,
-    ) -> Self {
-        let state = Arc::new(ServerState { clients });
-        Self {
-            listen,
-            state,
-            whitelist,
-            blacklist,
+    pub fn new(mut configuration: Configuration) -> anyhow::Result<Self> {
+        configuration.expand_ip_groups();
+        let keys = load_authorized_keys(&configuration.clients)?;
+        if configuration.server_id.is_none() {
+            warn_unchecked_audiences(&configuration.clients);
         }
+        let revocations = RevocationList::load(configuration.revocation_file)?;
+        let clock_skew = configuration.clock_skew.unwrap_or(DEFAULT_CLOCK_SKEW);
+        let max_token_lifetime = configuration
+            .max_token_lifetime
+            .unwrap_or(DEFAULT_MAX_TOKEN_LIFETIME + clock_skew);
+        let oidc = configuration
+            .oidc
+            .map(|oidc| OidcVerifier::new(oidc, clock_skew))
+            .transpose()
+            .context("Failed to initialize OIDC authentication")?;
+        let rate_limiter = configuration
+            .rate_limit
+            .map(|rate_limit| RateLimiter::new(rate_limit).map(Arc::new))
+            .transpose()
+            .context("Failed to initialize rate limiting")?;
+        let state = Arc::new(ServerState {
+            server_id: configuration.server_id,
+            clock_skew,
+            max_token_lifetime,
+            clients: configuration.clients,
+            keys,
+            revocations,
+            nonces: NonceCache::new(NONCE_CACHE_CAPACITY),
+            oidc,
+            rate_limiter: rate_limiter.clone(),
+        });
+        let client_ip = ClientIpResolver::new(
+            configuration.client_ip_header,
+            configuration
+                .trusted_proxies
+                .unwrap_or_else(default_trusted_proxies),
+        );
+        Ok(Self {
+            listen: configuration.listen,
+            state,
+            client_ip: Arc::new(client_ip),
+            rate_limiter,
+            whitelist: configuration.ip_whitelist,
+            blacklist: configuration.ip_blacklist,
+        })
     }
 
     pub async fn serve(&self) -> anyhow::Result<()> {
         let router = self.build_router();
 
+        if let Some(path) = self.state.revocations.path() {
+            let state = self.state.clone();
+            file_watcher::watch_file(path.clone(), move || state.revocations.reload());
+        }
+
+        if let Some(oidc) = &self.state.oidc {
+            if let Some(path) = oidc.jwks_file() {
+                let state = self.state.clone();
+                file_watcher::watch_file(path.clone(), move || {
+                    if let Some(oidc) = &state.oidc {
+                        oidc.reload();
+                    }
+                });
+            }
+            if oidc.jwks_url().is_some() {
+                oidc.download().await;
+                let state = self.state.clone();
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(JWKS_URL_REFRESH_INTERVAL);
+                    interval.tick().await;
+                    loop {
+                        interval.tick().await;
+                        if let Some(oidc) = &state.oidc {
+                            oidc.download().await;
+                        }
+                    }
+                });
+            }
+        }
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            let limiter = rate_limiter.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(RATE_LIMIT_PRUNE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    limiter.prune(Utc::now());
+                }
+            });
+
+            if let Some(address) = rate_limiter.metrics_address() {
+                let listener = tokio::net::TcpListener::bind(address)
+                    .await
+                    .with_context(|| "Failed to bind to metrics address")?;
+                let router = axum::Router::new()
+                    .route("/metrics", get(metrics_handler))
+                    .with_state(rate_limiter.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = axum::serve(listener, router).await {
+                        tracing::error!("Metrics server failed: {e}");
+                    }
+                });
+            }
+        }
+
         match &self.listen {
             ListenConfiguration::Tcp(address) => {
                 let listener = tokio::net::TcpListener::bind(address)
@@ -69,16 +211,36 @@ impl Server {
                     .with_context(|| "Failed to bind to TCP address")?;
                 axum::serve(
                     listener,
-                    router.into_make_service_with_connect_info::<SocketAddr>(),
+                    router.into_make_service_with_connect_info::<PeerInfo>(),
+                )
+                .await?;
+            }
+
+            ListenConfiguration::Tls(tls) => {
+                let resolver = Arc::new(
+                    CertificateResolver::load(tls.certificate.clone(), tls.private_key.clone())
+                        .with_context(|| "Failed to load TLS certificate")?,
+                );
+                for path in [resolver.certificate(), resolver.private_key()] {
+                    let resolver = resolver.clone();
+                    file_watcher::watch_file(path.clone(), move || resolver.reload());
+                }
+                let listener =
+                    TlsListener::bind(&tls.address, resolver, tls.client_ca.as_deref()).await?;
+                axum::serve(
+                    listener,
+                    router.into_make_service_with_connect_info::<PeerInfo>(),
                 )
                 .await?;
             }
 
-            ListenConfiguration::Socket(path) => {
-                let listener = tokio::net::UnixListener::bind(path).with_context(|| {
-                    format!("Failed to bind to unix socket path {}", path.display())
-                })?;
-                axum::serve(listener, router).await?;
+            ListenConfiguration::Socket(socket) => {
+                let listener = socket::bind(socket)?;
+                axum::serve(
+                    listener,
+                    router.into_make_service_with_connect_info::<PeerInfo>(),
+                )
+                .await?;
             }
         };
         Ok(())
@@ -90,6 +252,10 @@ impl Server {
             .with_state(self.state.clone())
             .layer(TraceLayer::new_for_http())
             .layer(AuthScope::Worker.into_extension())
+            .layer(middleware::from_fn_with_state(
+                self.rate_limiter.clone(),
+                rate_limit_layer,
+            ))
             .layer(middleware::from_fn_with_state(
                 self.whitelist.clone(),
                 whitelist_layer,
@@ -98,33 +264,57 @@ impl Server {
                 self.blacklist.clone(),
                 blacklist_layer,
             ))
-            .layer(ClientIpSource::RightmostXForwardedFor.into_extension())
+            .layer(middleware::from_fn_with_state(
+                self.client_ip.clone(),
+                client_ip_layer,
+            ))
+            .layer(middleware::map_response(advertise_protocol))
     }
 }
 
+/// Warns about clients whose tokens are accepted for any audience, i.e.
+/// also tokens minted for other servers that share their keys.
+fn warn_unchecked_audiences(clients: &[Client]) {
+    let unchecked: Vec<&str> = clients
+        .iter()
+        .filter(|e| e.allowed_audiences.is_none())
+        .map(|e| e.name.as_str())
+        .collect();
+    if !unchecked.is_empty() {
+        tracing::warn!(
+            "Token audiences are not checked for clients {}: tokens minted for any other server sharing their keys are accepted. Set server_id or allowed_audiences",
+            unchecked.join(", ")
+        );
+    }
+}
+
+/// Tells clients which protocol versions the server speaks, also when the
+/// connection is rejected.
+async fn advertise_protocol(mut response: Response) -> Response {
+    if let Ok(value) = HeaderValue::try_from(ProtocolRange::supported().to_string()) {
+        response.headers_mut().insert(PROTOCOL_HEADER, value);
+    }
+    response
+}
+
 async fn blacklist_layer(
-    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    client_address: Option<Extension<ClientAddress>>,
     State(blacklist): State<Option<Vec<IpCidr>>>,
     request: Request,
     next: Next,
 ) -> Response {
-    let Some(blacklist) = blacklist else {
+    let (Some(blacklist), Some(Extension(ClientAddress(ip)))) = (blacklist, client_address) else {
         return next.run(request).await;
     };
-    let (mut parts, body) = request.into_parts();
-    let ip = ClientIp::from_request_parts(&mut parts, &())
-        .await
-        .map(|e| e.0)
-        .unwrap_or_else(|_| remote_addr.ip());
     if blacklist.iter().any(|cidr| cidr.contains(&ip)) {
         tracing::warn!("Client {} is blacklisted", ip);
         return StatusCode::FORBIDDEN.into_response();
     }
-    next.run(Request::from_parts(parts, body)).await
+    next.run(request).await
 }
 
 async fn whitelist_layer(
-    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    client_address: Option<Extension<ClientAddress>>,
     State(whitelist): State<Option<Vec<IpCidr>>>,
     request: Request,
     next: Next,
@@ -132,14 +322,13 @@ async fn whitelist_layer(
     let Some(whitelist) = whitelist else {
         return next.run(request).await;
     };
-    let (mut parts, body) = request.into_parts();
-    let ip = ClientIp::from_request_parts(&mut parts, &())
-        .await
-        .map(|e| e.0)
-        .unwrap_or_else(|_| remote_addr.ip());
+    let Some(Extension(ClientAddress(ip))) = client_address else {
+        tracing::warn!("Client address is unknown, cannot check the whitelist");
+        return StatusCode::FORBIDDEN.into_response();
+    };
     if !whitelist.iter().any(|cidr| cidr.contains(&ip)) {
         tracing::warn!("Client {} is not whitelisted", ip);
         return StatusCode::FORBIDDEN.into_response();
     }
-    next.run(Request::from_parts(parts, body)).await
+    next.run(request).await
 }
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Remembers token identifiers (`jti`) until the tokens carrying them expire,
+/// so that a captured token cannot be used a second time.
+pub struct NonceCache {
+    capacity: usize,
+    entries: Mutex<HashMap<String, usize>>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum NonceCacheError {
+    Replayed,
+    Full,
+}
+
+impl NonceCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records `nonce` as used until `expires_at`. Entries whose expiration is
+    /// older than `now` are evicted first; if the cache is still full the
+    /// nonce is refused rather than letting an older one be forgotten early.
+    pub fn insert(
+        &self,
+        nonce: &str,
+        expires_at: usize,
+        now: usize,
+    ) -> Result<(), NonceCacheError> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.contains_key(nonce) {
+            return Err(NonceCacheError::Replayed);
+        }
+        if entries.len() >= self.capacity {
+            entries.retain(|_, expiration| *expiration >= now);
+        }
+        if entries.len() >= self.capacity {
+            return Err(NonceCacheError::Full);
+        }
+        entries.insert(nonce.to_string(), expires_at);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::nonce_cache::{NonceCache, NonceCacheError};
+
+    #[test]
+    fn rejects_replayed_nonce() {
+        let cache = NonceCache::new(10);
+        assert_eq!(cache.insert("a", 100, 50), Ok(()));
+        assert_eq!(cache.insert("a", 100, 60), Err(NonceCacheError::Replayed));
+    }
+
+    #[test]
+    fn evicts_expired_nonces_when_full() {
+        let cache = NonceCache::new(2);
+        assert_eq!(cache.insert("a", 100, 50), Ok(()));
+        assert_eq!(cache.insert("b", 200, 50), Ok(()));
+        assert_eq!(cache.insert("c", 300, 150), Ok(()));
+        assert_eq!(cache.insert("d", 300, 150), Err(NonceCacheError::Full));
+        assert_eq!(cache.insert("b", 300, 150), Err(NonceCacheError::Replayed));
+    }
+}
@@ -0,0 +1,444 @@
+use crate::api::oidc_token::parse_audience;
+use crate::client::Client;
+use crate::configuration::OidcConfiguration;
+use crate::server::auth_error::AuthError;
+use crate::server::token_validation::{check_issued_at, token_error, token_validation};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::VerifyingKey;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// How often a JWKS downloaded from a URL is refreshed.
+pub const JWKS_URL_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Minimum time between two downloads caused by tokens signed with an unknown key.
+const JWKS_MISSING_KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Claims of a GitHub Actions OIDC token used to identify the workflow.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OidcClaims {
+    #[serde(rename = "sub")]
+    pub(crate) sub: String,
+    #[serde(rename = "aud")]
+    pub(crate) aud: Audience,
+    #[serde(rename = "exp")]
+    pub(crate) exp: usize,
+    #[serde(rename = "iat", default)]
+    pub(crate) iat: Option<usize>,
+    #[serde(rename = "jti", default)]
+    pub(crate) jti: Option<String>,
+    #[serde(rename = "repository")]
+    pub(crate) repository: String,
+    #[serde(rename = "ref")]
+    pub(crate) git_ref: String,
+    #[serde(rename = "environment", default)]
+    pub(crate) environment: Option<String>,
+}
+
+/// The `aud` claim, a single audience or a list of them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audience {
+    fn values(&self) -> &[String] {
+        match self {
+            Audience::Single(value) => std::slice::from_ref(value),
+            Audience::Multiple(values) => values,
+        }
+    }
+}
+
+/// Validates tokens of the configured identity provider against its JWKS.
+pub struct OidcVerifier {
+    configuration: OidcConfiguration,
+    clock_skew: u64,
+    keys: RwLock<JwkSet>,
+    last_download: Mutex<Option<Instant>>,
+}
+
+impl OidcVerifier {
+    /// Reads the JWKS file right away; a JWKS URL is only downloaded by
+    /// [`OidcVerifier::download`].
+    pub fn new(configuration: OidcConfiguration, clock_skew: u64) -> anyhow::Result<Self> {
+        let keys = match &configuration.jwks_file {
+            Some(path) => read_jwks(path)?,
+            None => JwkSet { keys: Vec::new() },
+        };
+        Ok(Self {
+            configuration,
+            clock_skew,
+            keys: RwLock::new(keys),
+            last_download: Mutex::new(None),
+        })
+    }
+
+    pub fn jwks_file(&self) -> Option<&PathBuf> {
+        self.configuration.jwks_file.as_ref()
+    }
+
+    pub fn jwks_url(&self) -> Option<&str> {
+        self.configuration.jwks_url.as_deref()
+    }
+
+    /// Re-reads the JWKS file, keeping the previous keys if it cannot be read.
+    pub fn reload(&self) {
+        let Some(path) = &self.configuration.jwks_file else {
+            return;
+        };
+        match read_jwks(path) {
+            Ok(keys) => {
+                tracing::info!(
+                    "Reloaded JWKS {} with {} keys",
+                    path.display(),
+                    keys.keys.len()
+                );
+                *self.keys.write().unwrap_or_else(|e| e.into_inner()) = keys;
+            }
+            Err(e) => {
+                tracing::error!("Cannot reload JWKS, keeping the previous keys: {e:#}");
+            }
+        }
+    }
+
+    /// Downloads the JWKS from the configured URL, keeping the previous keys
+    /// if the download fails.
+    pub async fn download(&self) {
+        let Some(url) = &self.configuration.jwks_url else {
+            return;
+        };
+        *self.last_download.lock().await = Some(Instant::now());
+        match fetch_jwks(url).await {
+            Ok(keys) => {
+                tracing::info!("Downloaded JWKS {url} with {} keys", keys.keys.len());
+                *self.keys.write().unwrap_or_else(|e| e.into_inner()) = keys;
+            }
+            Err(e) => {
+                tracing::error!("Cannot download JWKS, keeping the previous keys: {e:#}");
+            }
+        }
+    }
+
+    fn find_key(&self, kid: Option<&str>) -> anyhow::Result<Option<DecodingKey>> {
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        let jwk = match kid {
+            Some(kid) => keys.find(kid),
+            None if keys.keys.len() == 1 => keys.keys.first(),
+            None => None,
+        };
+        jwk.map(DecodingKey::from_jwk)
+            .transpose()
+            .context("invalid key in JWKS")
+    }
+
+    async fn decoding_key(&self, kid: Option<&str>) -> anyhow::Result<DecodingKey> {
+        if let Some(key) = self.find_key(kid)? {
+            return Ok(key);
+        }
+        // The provider may have rotated its keys since the last download.
+        let recently_downloaded = self
+            .last_download
+            .lock()
+            .await
+            .is_some_and(|e| e.elapsed() < JWKS_MISSING_KEY_REFRESH_INTERVAL);
+        if self.jwks_url().is_some() && !recently_downloaded {
+            self.download().await;
+        }
+        self.find_key(kid)?
+            .with_context(|| format!("no key {} in JWKS", kid.unwrap_or("without id")))
+    }
+
+    fn validation(&self) -> Validation {
+        let mut validation = token_validation(Algorithm::RS256, self.clock_skew);
+        validation.set_issuer(&[&self.configuration.issuer]);
+        // Checked by `request_key`, as the audience may carry a request key.
+        validation.validate_aud = false;
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation
+    }
+
+    /// Verifies the token and returns its claims with the key task requests
+    /// must be signed with. Tokens without a request key are only accepted
+    /// with `allow_unbound_tokens`.
+    pub async fn verify(
+        &self,
+        token: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(OidcClaims, Option<VerifyingKey>), AuthError> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| {
+            tracing::error!("Invalid OIDC token: {e}");
+            AuthError::MalformedToken
+        })?;
+        if header.alg != Algorithm::RS256 {
+            tracing::error!("Unsupported OIDC token algorithm {:?}", header.alg);
+            return Err(AuthError::InvalidCredentials);
+        }
+        let key = self
+            .decoding_key(header.kid.as_deref())
+            .await
+            .map_err(|e| {
+                tracing::error!("Cannot verify OIDC token: {e:#}");
+                AuthError::InvalidCredentials
+            })?;
+        let validation = self.validation();
+        let claims = jsonwebtoken::decode::<OidcClaims>(token, &key, &validation)
+            .map_err(|e| {
+                tracing::error!("Invalid OIDC token: {e}");
+                token_error(&e, now)
+            })?
+            .claims;
+        if let Err(e) = check_issued_at(claims.iat, now, &validation) {
+            tracing::error!("OIDC token of {} was rejected: {e}", claims.sub);
+            return Err(e);
+        }
+        let request_key = self.request_key(&claims)?;
+        Ok((claims, request_key))
+    }
+
+    fn request_key(&self, claims: &OidcClaims) -> Result<Option<VerifyingKey>, AuthError> {
+        let Some(request_key) = claims
+            .aud
+            .values()
+            .iter()
+            .find_map(|e| parse_audience(e, &self.configuration.audience))
+        else {
+            tracing::error!(
+                "OIDC token of {} was issued for audience {:?}",
+                claims.sub,
+                claims.aud.values()
+            );
+            return Err(AuthError::InvalidAudience);
+        };
+        if request_key.is_none() && !self.configuration.allow_unbound_tokens {
+            tracing::error!(
+                "OIDC token of {} is not bound to a request key and allow_unbound_tokens is off",
+                claims.sub
+            );
+            return Err(AuthError::UnboundOidcToken);
+        }
+        Ok(request_key)
+    }
+}
+
+/// The first client with an `oidc` rule matching the workflow identity.
+pub fn find_client<'a>(clients: &'a [Client], claims: &OidcClaims) -> Option<&'a Client> {
+    clients.iter().find(|client| {
+        client.oidc.iter().flatten().any(|rule| {
+            rule.matches(
+                &claims.repository,
+                &claims.git_ref,
+                claims.environment.as_deref(),
+            )
+        })
+    })
+}
+
+fn read_jwks(path: &Path) -> anyhow::Result<JwkSet> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read JWKS file {}", path.display()))?;
+    serde_json::from_str(&contents).with_context(|| format!("Invalid JWKS in {}", path.display()))
+}
+
+async fn fetch_jwks(url: &str) -> anyhow::Result<JwkSet> {
+    // reqwest is built without a crypto provider of its own, use the ring
+    // provider rustls is compiled with. Fails harmlessly once installed.
+    _ = rustls::crypto::ring::default_provider().install_default();
+    reqwest::get(url)
+        .await
+        .and_then(|e| e.error_for_status())
+        .with_context(|| format!("Failed to download JWKS from {url}"))?
+        .json()
+        .await
+        .with_context(|| format!("Invalid JWKS at {url}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::oidc_token::bound_audience;
+    use crate::client::Client;
+    use crate::configuration::OidcConfiguration;
+    use crate::server::auth_error::AuthError;
+    use crate::server::oidc::{OidcVerifier, find_client};
+    use crate::server::token_validation::DEFAULT_CLOCK_SKEW;
+    use chrono::Utc;
+    use ed25519_dalek::{SigningKey, VerifyingKey};
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use std::path::PathBuf;
+
+    const ISSUER: &str = "https://token.actions.githubusercontent.com";
+
+    fn verifier() -> OidcVerifier {
+        verifier_with(false)
+    }
+
+    fn verifier_with(allow_unbound_tokens: bool) -> OidcVerifier {
+        OidcVerifier::new(
+            OidcConfiguration {
+                issuer: ISSUER.to_string(),
+                audience: "orosu".to_string(),
+                jwks_file: Some(PathBuf::from(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/tests/fixtures/oidc-jwks.json"
+                ))),
+                jwks_url: None,
+                allow_unbound_tokens,
+            },
+            DEFAULT_CLOCK_SKEW,
+        )
+        .unwrap()
+    }
+
+    fn claims(audience: &str, environment: Option<&str>) -> serde_json::Value {
+        let now = Utc::now().timestamp();
+        serde_json::json!({
+            "iss": ISSUER,
+            "aud": audience,
+            "sub": "repo:acme/web:ref:refs/heads/main",
+            "iat": now,
+            "nbf": now,
+            "exp": now + 300,
+            "jti": "token-id",
+            "repository": "acme/web",
+            "ref": "refs/heads/main",
+            "environment": environment,
+        })
+    }
+
+    fn sign(claims: &serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("test-key".to_string());
+        let key =
+            EncodingKey::from_rsa_pem(include_bytes!("../../tests/fixtures/oidc-key.pem")).unwrap();
+        jsonwebtoken::encode(&header, claims, &key).unwrap()
+    }
+
+    fn token(audience: &str, environment: Option<&str>) -> String {
+        sign(&claims(audience, environment))
+    }
+
+    fn request_key() -> VerifyingKey {
+        SigningKey::from_bytes(&[7; 32]).verifying_key()
+    }
+
+    fn bound(audience: &str) -> String {
+        bound_audience(audience, &request_key())
+    }
+
+    fn clients() -> Vec<Client> {
+        let yaml = r#"
+- name: "staging"
+  oidc:
+    - repository: "acme/web"
+      ref: "refs/heads/*"
+      environment: "staging"
+  scripts: []
+- name: "main"
+  oidc:
+    - repository: "acme/*"
+      ref: "refs/heads/main"
+  scripts: []
+"#;
+        serde_saphyr::from_str(yaml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn verifies_token_against_jwks_file() {
+        let verifier = verifier();
+        let claims = verifier
+            .verify(&token(&bound("orosu"), None), Utc::now())
+            .await
+            .unwrap()
+            .0;
+        assert_eq!(claims.repository, "acme/web");
+        assert_eq!(claims.git_ref, "refs/heads/main");
+        assert_eq!(claims.jti.as_deref(), Some("token-id"));
+
+        assert!(
+            verifier
+                .verify(&token(&bound("other"), None), Utc::now())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn requires_tokens_bound_to_a_request_key() {
+        let (_, key) = verifier()
+            .verify(&token(&bound("orosu"), None), Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(key, Some(request_key()));
+
+        let error = verifier()
+            .verify(&token("orosu", None), Utc::now())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, AuthError::UnboundOidcToken));
+
+        let (_, key) = verifier_with(true)
+            .verify(&token("orosu", None), Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(key, None);
+    }
+
+    #[tokio::test]
+    async fn maps_workflow_identity_to_client() {
+        let verifier = verifier();
+        let clients = clients();
+
+        let (claims, _) = verifier
+            .verify(&token(&bound("orosu"), None), Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(find_client(&clients, &claims).unwrap().name, "main");
+
+        let (claims, _) = verifier
+            .verify(&token(&bound("orosu"), Some("staging")), Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(find_client(&clients, &claims).unwrap().name, "staging");
+
+        let mut claims = claims;
+        claims.repository = "other/web".to_string();
+        assert!(find_client(&clients, &claims).is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_token_time_errors_within_clock_skew() {
+        let verifier = verifier();
+        let now = Utc::now().timestamp();
+        let skew = DEFAULT_CLOCK_SKEW as i64;
+
+        let mut claims = claims(&bound("orosu"), None);
+        claims["exp"] = (now - skew / 2).into();
+        assert!(verifier.verify(&sign(&claims), Utc::now()).await.is_ok());
+        claims["exp"] = (now - skew - 10).into();
+        let error = verifier.verify(&sign(&claims), Utc::now()).await;
+        assert!(matches!(error, Err(AuthError::Expired { .. })));
+
+        let mut claims = self::claims(&bound("orosu"), None);
+        claims["nbf"] = (now + skew + 10).into();
+        let error = verifier.verify(&sign(&claims), Utc::now()).await;
+        assert!(matches!(error, Err(AuthError::NotYetValid { .. })));
+
+        let mut claims = self::claims(&bound("orosu"), None);
+        claims["iat"] = (now + skew + 10).into();
+        let error = verifier.verify(&sign(&claims), Utc::now()).await;
+        assert!(matches!(error, Err(AuthError::IssuedInFuture { .. })));
+
+        let mut claims = self::claims(&bound("orosu"), None);
+        claims.as_object_mut().unwrap().remove("iat");
+        let error = verifier.verify(&sign(&claims), Utc::now()).await;
+        assert!(matches!(error, Err(AuthError::MissingClaim(_))));
+    }
+}
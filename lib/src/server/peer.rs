@@ -0,0 +1,135 @@
+use crate::server::tls::TlsListener;
+use axum::extract::connect_info::Connected;
+use axum::serve::IncomingStream;
+use rustls::pki_types::CertificateDer;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use x509_cert::Certificate;
+use x509_cert::der::asn1::{Ia5StringRef, PrintableStringRef, Utf8StringRef};
+use x509_cert::der::oid::db::rfc4519::CN;
+use x509_cert::der::{Decode, Tag, Tagged};
+use x509_cert::ext::pkix::SubjectAltName;
+use x509_cert::ext::pkix::name::GeneralName;
+
+/// Connection details available to the request handlers.
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    /// Address of the TCP peer, absent on Unix sockets.
+    pub address: Option<SocketAddr>,
+    /// Identities of the verified client certificate, if the TLS listener
+    /// requires one.
+    pub certificate_names: Option<Vec<String>>,
+    /// Process credentials of the Unix socket peer.
+    pub credentials: Option<PeerCredentials>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for PeerInfo {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self {
+            address: Some(*stream.remote_addr()),
+            certificate_names: None,
+            credentials: None,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Connected<IncomingStream<'_, tokio::net::UnixListener>> for PeerInfo {
+    fn connect_info(stream: IncomingStream<'_, tokio::net::UnixListener>) -> Self {
+        let credentials = match stream.io().peer_cred() {
+            Ok(credentials) => Some(PeerCredentials {
+                uid: credentials.uid(),
+                gid: credentials.gid(),
+            }),
+            Err(e) => {
+                tracing::error!("Cannot read Unix socket peer credentials: {e}");
+                None
+            }
+        };
+        Self {
+            address: None,
+            certificate_names: None,
+            credentials,
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for PeerInfo {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        let (_, connection) = stream.io().get_ref();
+        let certificate_names = connection
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .map(certificate_names);
+        Self {
+            address: Some(*stream.remote_addr()),
+            certificate_names,
+            credentials: None,
+        }
+    }
+}
+
+/// Subject common names and DNS, email and URI alternative names of the
+/// certificate. An unparsable certificate has no names.
+pub fn certificate_names(certificate: &CertificateDer<'_>) -> Vec<String> {
+    let certificate = match Certificate::from_der(certificate) {
+        Ok(certificate) => certificate,
+        Err(e) => {
+            tracing::error!("Cannot parse client certificate: {e}");
+            return Vec::new();
+        }
+    };
+    let tbs = &certificate.tbs_certificate;
+
+    let mut names: Vec<String> = tbs
+        .subject
+        .0
+        .iter()
+        .flat_map(|rdn| rdn.0.iter())
+        .filter(|attribute| attribute.oid == CN)
+        .filter_map(|attribute| {
+            let value = &attribute.value;
+            match value.tag() {
+                Tag::Utf8String => Utf8StringRef::try_from(value).ok().map(|e| e.to_string()),
+                Tag::PrintableString => PrintableStringRef::try_from(value)
+                    .ok()
+                    .map(|e| e.to_string()),
+                Tag::Ia5String => Ia5StringRef::try_from(value).ok().map(|e| e.to_string()),
+                _ => None,
+            }
+        })
+        .collect();
+
+    if let Ok(Some((_, SubjectAltName(alt_names)))) = tbs.get::<SubjectAltName>() {
+        names.extend(alt_names.iter().filter_map(|name| match name {
+            GeneralName::DnsName(name)
+            | GeneralName::Rfc822Name(name)
+            | GeneralName::UniformResourceIdentifier(name) => Some(name.to_string()),
+            _ => None,
+        }));
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::peer::certificate_names;
+    use rustls::pki_types::CertificateDer;
+    use rustls::pki_types::pem::PemObject;
+
+    #[test]
+    fn reads_subject_and_alternative_names() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls-cert.pem");
+        let certificate = CertificateDer::from_pem_file(path).unwrap();
+        assert_eq!(
+            certificate_names(&certificate),
+            vec!["localhost".to_string(), "localhost".to_string()]
+        );
+    }
+}
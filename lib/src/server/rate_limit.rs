@@ -0,0 +1,409 @@
+use crate::configuration::RateLimitConfiguration;
+use crate::server::auth_error::AuthFailure;
+use crate::server::client_ip::ClientAddress;
+use anyhow::Context;
+use axum::Extension;
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::http::header::CONTENT_TYPE;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Number of events within the current fixed window.
+#[derive(Debug, Clone, Copy)]
+struct Counter {
+    window_start: DateTime<Utc>,
+    count: u32,
+}
+
+/// Counts an event of `key` at `now` and returns the count in its window.
+fn count<K: Hash + Eq>(
+    counters: &mut HashMap<K, Counter>,
+    key: K,
+    now: DateTime<Utc>,
+    window: Duration,
+) -> u32 {
+    let counter = counters.entry(key).or_insert(Counter {
+        window_start: now,
+        count: 0,
+    });
+    if now - counter.window_start >= window {
+        *counter = Counter {
+            window_start: now,
+            count: 0,
+        };
+    }
+    counter.count += 1;
+    counter.count
+}
+
+#[derive(Default)]
+struct RateLimitState {
+    ip_attempts: HashMap<IpAddr, Counter>,
+    ip_failures: HashMap<IpAddr, Counter>,
+    client_attempts: HashMap<String, Counter>,
+    bans: HashMap<IpAddr, DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BanEntry {
+    #[serde(rename = "ip")]
+    ip: IpAddr,
+    #[serde(rename = "until")]
+    until: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StateFile {
+    #[serde(rename = "bans")]
+    bans: Vec<BanEntry>,
+}
+
+/// Why a connection attempt was refused before authentication.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Refusal {
+    Banned { until: DateTime<Utc> },
+    TooManyAttempts,
+}
+
+impl IntoResponse for Refusal {
+    fn into_response(self) -> Response {
+        match self {
+            Refusal::Banned { until } => (
+                StatusCode::FORBIDDEN,
+                format!(
+                    "address is banned after repeated authentication failures until {}",
+                    until.to_rfc3339_opts(SecondsFormat::Secs, true)
+                ),
+            )
+                .into_response(),
+            Refusal::TooManyAttempts => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many connection attempts, retry later",
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// Limits connection attempts per client address and per authenticated
+/// client and bans addresses that repeatedly fail to authenticate.
+pub struct RateLimiter {
+    configuration: RateLimitConfiguration,
+    state: Mutex<RateLimitState>,
+    save_lock: Mutex<()>,
+    failures_total: AtomicU64,
+    bans_total: AtomicU64,
+}
+
+/// Active bans as `ip until time` pairs for the log.
+fn describe_bans(bans: &HashMap<IpAddr, DateTime<Utc>>) -> String {
+    let mut bans: Vec<_> = bans.iter().collect();
+    bans.sort();
+    bans.iter()
+        .map(|(ip, until)| {
+            format!(
+                "{ip} until {}",
+                until.to_rfc3339_opts(SecondsFormat::Secs, true)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl RateLimiter {
+    pub fn new(configuration: RateLimitConfiguration) -> anyhow::Result<Self> {
+        let mut state = RateLimitState::default();
+        if let Some(path) = &configuration.state_file
+            && path.exists()
+        {
+            let now = Utc::now();
+            for entry in read_state_file(path)?.bans {
+                if entry.until > now {
+                    state.bans.insert(entry.ip, entry.until);
+                }
+            }
+            if !state.bans.is_empty() {
+                tracing::warn!("Active bans: {}", describe_bans(&state.bans));
+            }
+        }
+        Ok(Self {
+            configuration,
+            state: Mutex::new(state),
+            save_lock: Mutex::new(()),
+            failures_total: AtomicU64::new(0),
+            bans_total: AtomicU64::new(0),
+        })
+    }
+
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.configuration.metrics_address
+    }
+
+    fn window(&self) -> Duration {
+        Duration::seconds(self.configuration.window as i64)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RateLimitState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Counts a connection attempt of the address and refuses it if the
+    /// address is banned or exceeded its limit.
+    pub fn check(&self, ip: IpAddr, now: DateTime<Utc>) -> Result<(), Refusal> {
+        let window = self.window();
+        let mut state = self.lock();
+        if let Some(until) = state.bans.get(&ip).copied() {
+            if until > now {
+                return Err(Refusal::Banned { until });
+            }
+            state.bans.remove(&ip);
+            tracing::info!("Ban of address {ip} has expired");
+        }
+        if let Some(limit) = self.configuration.max_attempts_per_ip
+            && count(&mut state.ip_attempts, ip, now, window) > limit
+        {
+            tracing::warn!("Address {ip} exceeded {limit} connection attempts");
+            return Err(Refusal::TooManyAttempts);
+        }
+        Ok(())
+    }
+
+    /// Counts a connection of an authenticated client and refuses it if the
+    /// client exceeded its limit. Only verified identities are counted, so
+    /// that forged tokens cannot exhaust the limit of another client.
+    pub fn check_client(&self, name: &str, now: DateTime<Utc>) -> Result<(), Refusal> {
+        let Some(limit) = self.configuration.max_attempts_per_client else {
+            return Ok(());
+        };
+        let window = self.window();
+        let mut state = self.lock();
+        if count(&mut state.client_attempts, name.to_string(), now, window) > limit {
+            tracing::warn!("Client {name} exceeded {limit} connection attempts");
+            return Err(Refusal::TooManyAttempts);
+        }
+        Ok(())
+    }
+
+    /// Records a failed authentication and returns whether it got the
+    /// address banned.
+    pub fn record_failure(&self, ip: IpAddr, now: DateTime<Utc>) -> bool {
+        let window = self.window();
+        self.failures_total.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.lock();
+        let failures = count(&mut state.ip_failures, ip, now, window);
+        if failures < self.configuration.max_failures {
+            return false;
+        }
+
+        let until = now + Duration::seconds(self.configuration.ban_duration as i64);
+        state.ip_failures.remove(&ip);
+        state.bans.insert(ip, until);
+        self.bans_total.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(
+            "Banned address {ip} until {until} after {failures} authentication failures; active bans: {}",
+            describe_bans(&state.bans)
+        );
+        true
+    }
+
+    /// Drops counters of past windows and expired bans.
+    pub fn prune(&self, now: DateTime<Utc>) {
+        let window = self.window();
+        let mut state = self.lock();
+        state
+            .ip_attempts
+            .retain(|_, e| now - e.window_start < window);
+        state
+            .ip_failures
+            .retain(|_, e| now - e.window_start < window);
+        state
+            .client_attempts
+            .retain(|_, e| now - e.window_start < window);
+        let bans = state.bans.len();
+        state.bans.retain(|ip, until| {
+            let active = *until > now;
+            if !active {
+                tracing::info!("Ban of address {ip} has expired");
+            }
+            active
+        });
+        if state.bans.len() != bans && !state.bans.is_empty() {
+            tracing::info!("Active bans: {}", describe_bans(&state.bans));
+        }
+    }
+
+    /// Authentication failures and bans in the Prometheus text format.
+    pub fn metrics(&self, now: DateTime<Utc>) -> String {
+        let banned = self.lock().bans.values().filter(|e| **e > now).count();
+        format!(
+            "# HELP orosu_auth_failures_total Authentication failures with invalid credentials.\n\
+             # TYPE orosu_auth_failures_total counter\n\
+             orosu_auth_failures_total {}\n\
+             # HELP orosu_bans_total Addresses banned since the server started.\n\
+             # TYPE orosu_bans_total counter\n\
+             orosu_bans_total {}\n\
+             # HELP orosu_banned_addresses Addresses currently banned.\n\
+             # TYPE orosu_banned_addresses gauge\n\
+             orosu_banned_addresses {banned}\n",
+            self.failures_total.load(Ordering::Relaxed),
+            self.bans_total.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Writes the active bans to the state file, if one is configured.
+    pub fn save(&self) {
+        let Some(path) = &self.configuration.state_file else {
+            return;
+        };
+        let _guard = self.save_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let bans = self
+            .lock()
+            .bans
+            .iter()
+            .map(|(ip, until)| BanEntry {
+                ip: *ip,
+                until: *until,
+            })
+            .collect();
+        if let Err(e) = write_state_file(path, &StateFile { bans }) {
+            tracing::error!("Cannot save bans: {e:#}");
+        }
+    }
+}
+
+fn read_state_file(path: &Path) -> anyhow::Result<StateFile> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read ban state file {}", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse ban state file {}", path.display()))
+}
+
+fn write_state_file(path: &PathBuf, state: &StateFile) -> anyhow::Result<()> {
+    let directory = path
+        .parent()
+        .filter(|e| !e.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut file = tempfile::NamedTempFile::new_in(directory)
+        .with_context(|| format!("Failed to create a file in {}", directory.display()))?;
+    serde_json::to_writer(&mut file, state)?;
+    file.flush()?;
+    file.persist(path)
+        .with_context(|| format!("Failed to write ban state file {}", path.display()))?;
+    Ok(())
+}
+
+pub async fn rate_limit_layer(
+    client_address: Option<Extension<ClientAddress>>,
+    State(limiter): State<Option<Arc<RateLimiter>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = limiter else {
+        return next.run(request).await;
+    };
+    let ip = client_address.map(|Extension(ClientAddress(ip))| ip);
+    if let Some(ip) = ip
+        && let Err(refusal) = limiter.check(ip, Utc::now())
+    {
+        return refusal.into_response();
+    }
+
+    let response = next.run(request).await;
+    if response.extensions().get::<AuthFailure>().is_some()
+        && let Some(ip) = ip
+        && limiter.record_failure(ip, Utc::now())
+    {
+        tokio::task::spawn_blocking(move || limiter.save());
+    }
+    response
+}
+
+/// Serves the rate limiting metrics on `/metrics`.
+pub async fn metrics_handler(State(limiter): State<Arc<RateLimiter>>) -> Response {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        limiter.metrics(Utc::now()),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::RateLimitConfiguration;
+    use crate::server::rate_limit::{RateLimiter, Refusal};
+    use chrono::{Duration, Utc};
+    use std::net::IpAddr;
+
+    fn configuration() -> RateLimitConfiguration {
+        RateLimitConfiguration {
+            window: 60,
+            max_attempts_per_ip: Some(3),
+            max_attempts_per_client: Some(4),
+            max_failures: 2,
+            ban_duration: 600,
+            state_file: None,
+            metrics_address: None,
+        }
+    }
+
+    #[test]
+    fn limits_attempts_per_window() {
+        let limiter = RateLimiter::new(configuration()).unwrap();
+        let now = Utc::now();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..3 {
+            assert_eq!(limiter.check(ip, now), Ok(()));
+        }
+        assert_eq!(limiter.check(ip, now), Err(Refusal::TooManyAttempts));
+        let later = now + Duration::seconds(61);
+        assert_eq!(limiter.check(ip, later), Ok(()));
+
+        for _ in 0..4 {
+            assert_eq!(limiter.check_client("ci", now), Ok(()));
+        }
+        assert_eq!(
+            limiter.check_client("ci", now),
+            Err(Refusal::TooManyAttempts)
+        );
+        assert_eq!(limiter.check_client("other", now), Ok(()));
+    }
+
+    #[test]
+    fn bans_after_repeated_failures_and_persists_bans() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut configuration = configuration();
+        configuration.state_file = Some(directory.path().join("bans.json"));
+        let limiter = RateLimiter::new(configuration.clone()).unwrap();
+        let now = Utc::now();
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert!(!limiter.record_failure(ip, now));
+        assert!(limiter.record_failure(ip, now));
+        let until = now + Duration::seconds(600);
+        assert_eq!(limiter.check(ip, now), Err(Refusal::Banned { until }));
+        limiter.save();
+        let metrics = limiter.metrics(now);
+        assert!(metrics.contains("\norosu_auth_failures_total 2\n"));
+        assert!(metrics.contains("\norosu_bans_total 1\n"));
+        assert!(metrics.contains("\norosu_banned_addresses 1\n"));
+        assert!(
+            limiter
+                .metrics(until)
+                .contains("\norosu_banned_addresses 0\n")
+        );
+
+        let restarted = RateLimiter::new(configuration).unwrap();
+        assert_eq!(restarted.check(ip, now), Err(Refusal::Banned { until }));
+        assert_eq!(restarted.check(ip, until), Ok(()));
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::Context;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// Fingerprints of public keys that must no longer be accepted, loaded from
+/// the configured revocation file.
+pub struct RevocationList {
+    path: Option<PathBuf>,
+    fingerprints: RwLock<HashSet<String>>,
+}
+
+impl RevocationList {
+    pub fn load(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let fingerprints = match &path {
+            Some(path) => read_fingerprints(path)?,
+            None => HashSet::new(),
+        };
+        Ok(Self {
+            path,
+            fingerprints: RwLock::new(fingerprints),
+        })
+    }
+
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.path.as_ref()
+    }
+
+    pub fn is_revoked(&self, fingerprint: &str) -> bool {
+        self.fingerprints
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(fingerprint)
+    }
+
+    /// Re-reads the revocation file, keeping the previous list if it cannot be read.
+    pub fn reload(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        match read_fingerprints(path) {
+            Ok(fingerprints) => {
+                tracing::info!(
+                    "Reloaded revocation list {} with {} keys",
+                    path.display(),
+                    fingerprints.len()
+                );
+                *self.fingerprints.write().unwrap_or_else(|e| e.into_inner()) = fingerprints;
+            }
+            Err(e) => {
+                tracing::error!("Cannot reload revocation list, keeping the previous one: {e:#}");
+            }
+        }
+    }
+}
+
+fn read_fingerprints(path: &Path) -> anyhow::Result<HashSet<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read revocation file {}", path.display()))?;
+    parse_fingerprints(&contents)
+        .with_context(|| format!("Invalid revocation file {}", path.display()))
+}
+
+/// One `SHA256:` fingerprint per line, as printed by `orosu-keygen fingerprint`
+/// or `ssh-keygen -l`, so the bit length before it and the comment after it
+/// are ignored. Lines starting with `#` are comments. A line without a
+/// fingerprint is an error rather than skipped, since skipping it would leave
+/// the key accepted.
+fn parse_fingerprints(contents: &str) -> anyhow::Result<HashSet<String>> {
+    contents
+        .lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            line.split_whitespace()
+                .find(|e| e.starts_with("SHA256:"))
+                .map(String::from)
+                .with_context(|| format!("line {} has no SHA256 fingerprint: {line}", index + 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::revocation::parse_fingerprints;
+
+    #[test]
+    fn parse_revocation_file() {
+        let contents = r#"
+# leaked on 2025-01-01
+SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU
+SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s ci runner key
+"#;
+        let fingerprints = parse_fingerprints(contents).unwrap();
+        assert_eq!(fingerprints.len(), 2);
+        assert!(fingerprints.contains("SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU"));
+        assert!(fingerprints.contains("SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s"));
+    }
+
+    #[test]
+    fn parse_ssh_keygen_output() {
+        let contents =
+            "256 SHA256:pA5ubr8fWcdxbxl8wFnsLIfpvMvIwEaZagsemjC0jDI ci@runner (ED25519)\n";
+        let fingerprints = parse_fingerprints(contents).unwrap();
+        assert_eq!(
+            fingerprints.into_iter().collect::<Vec<_>>(),
+            vec!["SHA256:pA5ubr8fWcdxbxl8wFnsLIfpvMvIwEaZagsemjC0jDI"]
+        );
+
+        let md5 = "256 MD5:e9:e7:77:3a:95:a3:78:5e:27:86:c8:c2:9b:21:cf:4c ci@runner (ED25519)";
+        assert!(parse_fingerprints(md5).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use crate::configuration::SocketListenConfiguration;
+use anyhow::Context;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tokio::net::UnixListener;
+
+/// Binds the Unix socket with the configured mode and ownership. The socket
+/// is created in a private directory next to `path`, which only the server
+/// user can enter, and is linked into place once the mode and owner are set.
+/// Other users therefore cannot connect before the access control applies.
+pub fn bind(configuration: &SocketListenConfiguration) -> anyhow::Result<UnixListener> {
+    let path = configuration.path();
+    let Some(options) = configuration.options() else {
+        return UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind to unix socket path {}", path.display()));
+    };
+
+    let parent = path
+        .parent()
+        .filter(|e| !e.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let directory = tempfile::Builder::new()
+        .prefix(".orosu-socket-")
+        .permissions(std::fs::Permissions::from_mode(0o700))
+        .tempdir_in(parent)
+        .with_context(|| format!("Failed to create a directory in {}", parent.display()))?;
+    let staged = directory.path().join("socket");
+    let listener = UnixListener::bind(&staged)
+        .with_context(|| format!("Failed to bind to unix socket path {}", path.display()))?;
+
+    if let Some(mode) = options.mode()? {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set the mode of {}", path.display()))?;
+    }
+    let uid = options
+        .owner
+        .as_deref()
+        .map(|name| {
+            users::get_user_by_name(name)
+                .map(|user| user.uid())
+                .with_context(|| format!("Socket owner {name} not found"))
+        })
+        .transpose()?;
+    let gid = options
+        .group
+        .as_deref()
+        .map(|name| {
+            users::get_group_by_name(name)
+                .map(|group| group.gid())
+                .with_context(|| format!("Socket group {name} not found"))
+        })
+        .transpose()?;
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(&staged, uid, gid)
+            .with_context(|| format!("Failed to change the owner of {}", path.display()))?;
+    }
+    // Unlike a rename, a link fails if the path exists, as binding it would.
+    std::fs::hard_link(&staged, path)
+        .with_context(|| format!("Failed to bind to unix socket path {}", path.display()))?;
+    Ok(listener)
+}
@@ -0,0 +1,228 @@
+use anyhow::Context;
+use axum::serve::Listener;
+use rustls::RootCertStore;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+
+/// Time a client has to complete the TLS handshake before it is disconnected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificate chain and private key read from PEM files, replaced in place
+/// when the files are renewed.
+pub struct CertificateResolver {
+    certificate: PathBuf,
+    private_key: PathBuf,
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertificateResolver {
+    pub fn load(certificate: PathBuf, private_key: PathBuf) -> anyhow::Result<Self> {
+        let key = read_certified_key(&certificate, &private_key)?;
+        Ok(Self {
+            certificate,
+            private_key,
+            key: RwLock::new(Arc::new(key)),
+        })
+    }
+
+    pub fn certificate(&self) -> &PathBuf {
+        &self.certificate
+    }
+
+    pub fn private_key(&self) -> &PathBuf {
+        &self.private_key
+    }
+
+    /// Re-reads the certificate and key, keeping the previous ones if they
+    /// cannot be read or do not match, e.g. when only one of them has been
+    /// replaced so far.
+    pub fn reload(&self) {
+        match read_certified_key(&self.certificate, &self.private_key) {
+            Ok(key) => {
+                tracing::info!("Reloaded TLS certificate {}", self.certificate.display());
+                *self.key.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(key);
+            }
+            Err(e) => {
+                tracing::error!("Cannot reload TLS certificate, keeping the previous one: {e:#}");
+            }
+        }
+    }
+}
+
+impl Debug for CertificateResolver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertificateResolver")
+            .field("certificate", &self.certificate)
+            .field("private_key", &self.private_key)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap_or_else(|e| e.into_inner()).clone())
+    }
+}
+
+fn read_certified_key(certificate: &Path, private_key: &Path) -> anyhow::Result<CertifiedKey> {
+    let chain = CertificateDer::pem_file_iter(certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificate {}", certificate.display()))?;
+    if chain.is_empty() {
+        anyhow::bail!("No certificate found in {}", certificate.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(private_key)
+        .with_context(|| format!("Failed to read private key {}", private_key.display()))?;
+    let key = CertifiedKey::from_der(chain, key, &default_provider()).with_context(|| {
+        format!(
+            "Private key {} does not match certificate {}",
+            private_key.display(),
+            certificate.display()
+        )
+    })?;
+    Ok(key)
+}
+
+/// Certificate authorities that client certificates must be issued by.
+fn read_client_ca(path: &Path) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for certificate in CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("Failed to read client CA {}", path.display()))?
+    {
+        let certificate =
+            certificate.with_context(|| format!("Failed to read client CA {}", path.display()))?;
+        roots
+            .add(certificate)
+            .with_context(|| format!("Invalid client CA certificate in {}", path.display()))?;
+    }
+    if roots.is_empty() {
+        anyhow::bail!("No certificate found in {}", path.display());
+    }
+    Ok(roots)
+}
+
+/// TCP listener terminating TLS. Handshakes run concurrently, so a slow
+/// client does not hold up the others.
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    handshakes: JoinSet<Option<(TlsStream<TcpStream>, SocketAddr)>>,
+}
+
+impl TlsListener {
+    /// Binds to `address`. With a `client_ca`, only clients presenting a
+    /// certificate issued by it can connect.
+    pub async fn bind(
+        address: &SocketAddr,
+        resolver: Arc<CertificateResolver>,
+        client_ca: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let provider = Arc::new(default_provider());
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .with_context(|| "Failed to configure TLS")?;
+        let builder = match client_ca {
+            Some(path) => {
+                let roots = Arc::new(read_client_ca(path)?);
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider)
+                    .build()
+                    .with_context(|| "Failed to configure client certificate verification")?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(resolver);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let listener = TcpListener::bind(address)
+            .await
+            .with_context(|| "Failed to bind to TCP address")?;
+        Ok(Self {
+            listener,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            handshakes: JoinSet::new(),
+        })
+    }
+}
+
+async fn handshake(
+    acceptor: TlsAcceptor,
+    stream: TcpStream,
+    address: SocketAddr,
+) -> Option<(TlsStream<TcpStream>, SocketAddr)> {
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => Some((stream, address)),
+        Ok(Err(e)) => {
+            tracing::debug!("TLS handshake with {address} failed: {e}");
+            None
+        }
+        Err(_) => {
+            tracing::debug!("TLS handshake with {address} timed out");
+            None
+        }
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            tokio::select! {
+                (stream, address) = Listener::accept(&mut self.listener) => {
+                    self.handshakes.spawn(handshake(self.acceptor.clone(), stream, address));
+                }
+                Some(result) = self.handshakes.join_next(), if !self.handshakes.is_empty() => {
+                    if let Ok(Some(connection)) = result {
+                        return connection;
+                    }
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::tls::CertificateResolver;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn reload_keeps_certificate_on_mismatch() {
+        let directory = tempfile::tempdir().unwrap();
+        let certificate = directory.path().join("cert.pem");
+        let private_key = directory.path().join("key.pem");
+        std::fs::copy(fixture("tls-cert.pem"), &certificate).unwrap();
+        std::fs::copy(fixture("tls-key.pem"), &private_key).unwrap();
+
+        let resolver = CertificateResolver::load(certificate, private_key.clone()).unwrap();
+        let loaded = resolver.key.read().unwrap().clone();
+
+        std::fs::copy(fixture("oidc-key.pem"), &private_key).unwrap();
+        resolver.reload();
+        let reloaded = resolver.key.read().unwrap().clone();
+        assert!(std::sync::Arc::ptr_eq(&loaded, &reloaded));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::api::client::DEFAULT_TOKEN_LIFETIME;
+use crate::server::auth_error::AuthError;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, Validation};
+
+/// Clock difference tolerated between clients and the server, in seconds.
+pub const DEFAULT_CLOCK_SKEW: u64 = 60;
+
+/// Longest lifetime of client tokens accepted by default, on top of the
+/// clock skew. Token identifiers are only remembered in memory until the
+/// tokens expire, so long-lived tokens could be replayed after a restart.
+pub const DEFAULT_MAX_TOKEN_LIFETIME: u64 = 6 * DEFAULT_TOKEN_LIFETIME;
+
+/// Validation shared by every kind of token: `exp` and `nbf` are checked with
+/// `clock_skew` seconds of leeway. `jsonwebtoken` does not check `iat`, which
+/// is left to [`check_issued_at`].
+pub fn token_validation(algorithm: Algorithm, clock_skew: u64) -> Validation {
+    let mut validation = Validation::new(algorithm);
+    validation.leeway = clock_skew;
+    validation.validate_nbf = true;
+    validation.set_required_spec_claims(&["exp", "sub"]);
+    validation
+}
+
+/// Rejects tokens without `iat` or claiming to be issued later than `now`,
+/// allowing for the same clock skew as the expiration.
+pub fn check_issued_at(
+    issued_at: Option<usize>,
+    now: DateTime<Utc>,
+    validation: &Validation,
+) -> Result<(), AuthError> {
+    let Some(issued_at) = issued_at else {
+        return Err(AuthError::MissingClaim("iat".to_string()));
+    };
+    if issued_at as i64 > now.timestamp() + validation.leeway as i64 {
+        return Err(AuthError::IssuedInFuture { server_time: now });
+    }
+    Ok(())
+}
+
+/// Rejects tokens valid for longer than `max_lifetime` seconds from their
+/// issue time.
+pub fn check_lifetime(
+    issued_at: usize,
+    expires_at: usize,
+    max_lifetime: u64,
+) -> Result<(), AuthError> {
+    if expires_at.saturating_sub(issued_at) as u64 > max_lifetime {
+        return Err(AuthError::LifetimeTooLong { max_lifetime });
+    }
+    Ok(())
+}
+
+/// Maps a failed `jsonwebtoken` validation to the reason reported to the client.
+pub fn token_error(error: &jsonwebtoken::errors::Error, now: DateTime<Utc>) -> AuthError {
+    match error.kind() {
+        ErrorKind::ExpiredSignature => AuthError::Expired { server_time: now },
+        ErrorKind::ImmatureSignature => AuthError::NotYetValid { server_time: now },
+        ErrorKind::InvalidAudience => AuthError::InvalidAudience,
+        ErrorKind::InvalidIssuer => AuthError::InvalidIssuer,
+        ErrorKind::MissingRequiredClaim(claim) => AuthError::MissingClaim(claim.clone()),
+        ErrorKind::InvalidToken | ErrorKind::Base64(_) | ErrorKind::Json(_) => {
+            AuthError::MalformedToken
+        }
+        _ => AuthError::InvalidCredentials,
+    }
+}
+
+/// Whether the token signature was checked successfully before `error` was
+/// raised, i.e. the key is right and only the claims were rejected. Claims
+/// are deserialized after the signature check, so JSON errors count as well.
+pub fn is_claim_error(error: &jsonwebtoken::errors::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::ExpiredSignature
+            | ErrorKind::ImmatureSignature
+            | ErrorKind::InvalidAudience
+            | ErrorKind::InvalidIssuer
+            | ErrorKind::InvalidSubject
+            | ErrorKind::MissingRequiredClaim(_)
+            | ErrorKind::Json(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::auth_error::AuthError;
+    use crate::server::token_validation::check_lifetime;
+
+    #[test]
+    fn rejects_tokens_valid_for_too_long() {
+        assert!(check_lifetime(1000, 1060, 60).is_ok());
+        assert!(matches!(
+            check_lifetime(1000, 1061, 60),
+            Err(AuthError::LifetimeTooLong { max_lifetime: 60 })
+        ));
+        assert!(check_lifetime(1000, 900, 60).is_ok());
+    }
+}
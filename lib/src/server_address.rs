@@ -18,6 +18,11 @@ impl ServerAddress {
         let uri = Uri::from_parts(parts)?;
         Ok(ServerAddress(uri))
     }
+
+    /// Default token audience for this server, its lowercase host name.
+    pub fn audience(&self) -> String {
+        self.0.host().unwrap_or_default().to_lowercase()
+    }
 }
 
 impl Deref for ServerAddress {
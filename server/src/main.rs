@@ -18,18 +18,15 @@ async fn main() -> anyhow::Result<()> {
         .context("unable to load configuration file")?;
 
     tracing_subscriber::fmt()
-        .with_max_level(LevelFilter::from_level(configuration.log_level.into()))
+        .with_max_level(LevelFilter::from_level(
+            configuration.log_level.clone().into(),
+        ))
         .compact()
         .init();
 
     tracing::debug!("Starting Orosu server");
 
-    let server = Server::new(
-        configuration.listen,
-        configuration.ip_whitelist,
-        configuration.ip_blacklist,
-        configuration.clients,
-    );
+    let server = Server::new(configuration).context("unable to initialize server")?;
 
     server.serve().await?;
 